@@ -1,10 +1,11 @@
 from dataclasses import dataclass, asdict
 from multiprocessing.connection import Connection
 from dacite import from_dict
-from threading import Thread, ThreadError
+from threading import Thread, ThreadError, Timer
 from argparse import ArgumentParser
 from multiprocessing import Pipe
 from typing import Optional, ClassVar, TypeVar
+import hmac
 import random
 import string
 import traceback
@@ -15,6 +16,8 @@ import json
 
 T = TypeVar("T")
 
+RECONNECT_GRACE = 30.0
+
 
 @dataclass
 class Message:
@@ -31,8 +34,12 @@ class ClientConnectionResponse:
 @dataclass
 class CreateRoomRequest:
     max_clients: int
-    send_port: int
-    recv_port: int
+    port: int
+    public_key: str
+    name: str
+    level: str
+    public: bool
+    password: Optional[str]
 
 
 @dataclass
@@ -44,21 +51,54 @@ class CreateRoomResponse:
 @dataclass
 class JoinRoomRequest:
     room_id: str
-    send_port: int
-    recv_port: int
+    port: int
+    public_key: str
+    name: str
+    password: Optional[str]
+
+
+@dataclass
+class RoomInfo:
+    room_id: str
+    players: int
+    max_clients: int
+    level: str
+    has_password: bool
+
+
+@dataclass
+class RoomListResponse:
+    rooms: list[RoomInfo]
+    TYPE: ClassVar[str] = "@response room/list"
+
+
+@dataclass
+class LeaveRoomRequest:
+    room_id: str
+
+
+@dataclass
+class RejoinRoomRequest:
+    room_id: str
+    client_id: int
+    reconnect_token: str
+    port: int
+    public_key: str
+    name: str
 
 
 @dataclass
 class NetworkData:
     ip: str
-    send_port: int
-    recv_port: int
+    port: int
 
 
 @dataclass
 class ClientData:
     client_id: int
     network_data: NetworkData
+    public_key: str
+    name: str
 
 
 @dataclass
@@ -67,14 +107,38 @@ class JoinRoomResponse:
     room_id: str
     msg: Optional[str]
     host_data: Optional[ClientData]
+    reconnect_token: Optional[str]
     TYPE: ClassVar[str] = "@response room/join"
 
 
+@dataclass
+class RejoinRoomResponse(JoinRoomResponse):
+    TYPE: ClassVar[str] = "@response room/rejoin"
+
+
 @dataclass
 class JoinRoomNotification(ClientData):
     TYPE: ClassVar[str] = "@notification room/join"
 
 
+@dataclass
+class RejoinRoomNotification(ClientData):
+    TYPE: ClassVar[str] = "@notification room/rejoin"
+
+
+@dataclass
+class RelayMessage:
+    client_id: int
+    payload: str
+    TYPE: ClassVar[str] = "@relay"
+
+
+@dataclass
+class LeaveRoomNotification:
+    client_id: int
+    TYPE: ClassVar[str] = "@notification room/leave"
+
+
 @dataclass
 class Client:
     id: int
@@ -86,8 +150,11 @@ class Client:
 @dataclass
 class RoomClient:
     id: int
-    send_port: int
-    recv_port: int
+    port: int
+    public_key: str
+    name: str
+    reconnect_token: str
+    connected: bool = True
 
 
 ClientId = int
@@ -99,6 +166,9 @@ class RoomInstance:
     max_clients: int
     host_id: int
     clients: dict[ClientId, RoomClient]
+    level: str
+    public: bool
+    password: Optional[str]
 
 
 class Clients:
@@ -114,6 +184,9 @@ class Clients:
     def __setitem__(self, key: ClientId, value: Client):
         self.clients[key] = value
 
+    def __delitem__(self, key: ClientId):
+        del self.clients[key]
+
 
 clients: Clients = Clients()
 
@@ -131,6 +204,10 @@ rooms: dict[str, RoomInstance] = {}
 ROOM_ID_CHARS = string.ascii_letters
 
 
+def generate_reconnect_token():
+    return ''.join(random.choice(string.ascii_letters + string.digits) for _ in range(32))
+
+
 def create_room(request: CreateRoomRequest, client: Client):
     def generate_id():
         return ''.join(random.choice(ROOM_ID_CHARS) for _ in range(6))
@@ -147,13 +224,18 @@ def create_room(request: CreateRoomRequest, client: Client):
         clients={
              client.id: RoomClient(
                 id=client.id,
-                send_port=request.send_port,
-                recv_port=request.recv_port,
+                port=request.port,
+                public_key=request.public_key,
+                name=request.name,
+                reconnect_token=generate_reconnect_token(),
             ),
         },
+        level=request.level,
+        public=request.public,
+        password=request.password,
     )
 
-    print(f"Client {client.id} created room with id {room_id}")
+    print(f"{request.name} (client {client.id}) created room with id {room_id}")
 
     client.tx.send(asdict(Message(
         type=CreateRoomResponse.TYPE,
@@ -172,13 +254,44 @@ def join_room(request: JoinRoomRequest, client: Client):
                 room_id=request.room_id,
                 msg="Room not found",
                 host_data=None,
+                reconnect_token=None,
             ),
         )))
         return
 
     room = rooms[request.room_id]
+
+    if room.password is not None and not hmac.compare_digest(
+        (request.password or "").encode(), room.password.encode()
+    ):
+        client.tx.send(asdict(Message(
+            type=JoinRoomResponse.TYPE,
+            data=JoinRoomResponse(
+                success=False,
+                room_id=request.room_id,
+                msg="Incorrect password",
+                host_data=None,
+                reconnect_token=None,
+            ),
+        )))
+        return
+
+    if len(room.clients) >= room.max_clients:
+        client.tx.send(asdict(Message(
+            type=JoinRoomResponse.TYPE,
+            data=JoinRoomResponse(
+                success=False,
+                room_id=request.room_id,
+                msg="Room is full",
+                host_data=None,
+                reconnect_token=None,
+            ),
+        )))
+        return
+
     room_host = room.clients[room.host_id]
     host = clients[room.host_id]
+    reconnect_token = generate_reconnect_token()
 
     client.tx.send(asdict(Message(
         type=JoinRoomResponse.TYPE,
@@ -187,13 +300,15 @@ def join_room(request: JoinRoomRequest, client: Client):
             room_id=request.room_id,
             msg=None,
             host_data=ClientData(
-                client_id=client.id,
+                client_id=room.host_id,
                 network_data=NetworkData(
                     ip=host.ip,
-                    send_port=room_host.send_port,
-                    recv_port=room_host.recv_port,
+                    port=room_host.port,
                 ),
+                public_key=room_host.public_key,
+                name=room_host.name,
             ),
+            reconnect_token=reconnect_token,
         ),
     )))
 
@@ -203,19 +318,200 @@ def join_room(request: JoinRoomRequest, client: Client):
             client_id=client.id,
             network_data=NetworkData(
                 ip=client.ip,
-                send_port=request.send_port,
-                recv_port=request.recv_port,
+                port=request.port,
             ),
+            public_key=request.public_key,
+            name=request.name,
         ),
     )))
 
     rooms[request.room_id].clients[client.id] = RoomClient(
         id=client.id,
-        send_port=request.send_port,
-        recv_port=request.recv_port,
+        port=request.port,
+        public_key=request.public_key,
+        name=request.name,
+        reconnect_token=reconnect_token,
     )
 
 
+def list_rooms(client: Client):
+    # Private rooms can still be joined by anyone who knows the id, they just aren't advertised
+    client.tx.send(asdict(Message(
+        type=RoomListResponse.TYPE,
+        data=RoomListResponse(
+            rooms=[
+                RoomInfo(
+                    room_id=room.id,
+                    players=len(room.clients),
+                    max_clients=room.max_clients,
+                    level=room.level,
+                    has_password=room.password is not None,
+                )
+                for room in rooms.values()
+                if room.public
+            ],
+        ),
+    )))
+
+
+def rejoin_room(request: RejoinRoomRequest, client: Client):
+    def reject(msg: str):
+        client.tx.send(asdict(Message(
+            type=RejoinRoomResponse.TYPE,
+            data=RejoinRoomResponse(
+                success=False,
+                room_id=request.room_id,
+                msg=msg,
+                host_data=None,
+                reconnect_token=None,
+            ),
+        )))
+
+    room = rooms.get(request.room_id)
+
+    if room is None:
+        reject("Room not found")
+        return
+
+    room_client = room.clients.get(request.client_id)
+
+    if room_client is None or room_client.reconnect_token != request.reconnect_token:
+        reject("Invalid reconnect token")
+        return
+
+    if request.client_id == room.host_id:
+        reject("The host cannot rejoin its own room")
+        return
+
+    # The new connection takes over the id the client had when it joined
+    if client.id != request.client_id:
+        del clients[client.id]
+        client.id = request.client_id
+        clients[client.id] = client
+
+    room_client.port = request.port
+    room_client.public_key = request.public_key
+    room_client.name = request.name
+    room_client.connected = True
+
+    room_host = room.clients[room.host_id]
+    host = clients[room.host_id]
+
+    client.tx.send(asdict(Message(
+        type=RejoinRoomResponse.TYPE,
+        data=RejoinRoomResponse(
+            success=True,
+            room_id=request.room_id,
+            msg=None,
+            host_data=ClientData(
+                client_id=room.host_id,
+                network_data=NetworkData(
+                    ip=host.ip,
+                    port=room_host.port,
+                ),
+                public_key=room_host.public_key,
+                name=room_host.name,
+            ),
+            reconnect_token=room_client.reconnect_token,
+        ),
+    )))
+
+    host.tx.send(asdict(Message(
+        type=RejoinRoomNotification.TYPE,
+        data=RejoinRoomNotification(
+            client_id=client.id,
+            network_data=NetworkData(
+                ip=client.ip,
+                port=request.port,
+            ),
+            public_key=request.public_key,
+            name=request.name,
+        ),
+    )))
+
+    print(f"{request.name} (client {client.id}) rejoined room {request.room_id}")
+
+
+def remove_from_room(room: RoomInstance, client_id: ClientId):
+    del room.clients[client_id]
+
+    if client_id == room.host_id:
+        del rooms[room.id]
+        print(f"Room {room.id} closed as its host left")
+
+    for member_id, member in room.clients.items():
+        if not member.connected:
+            continue
+
+        clients[member_id].tx.send(asdict(Message(
+            type=LeaveRoomNotification.TYPE,
+            data=LeaveRoomNotification(
+                client_id=client_id,
+            ),
+        )))
+
+    print(f"Client {client_id} left room {room.id}")
+
+
+def expire_slot(room_id: str, client_id: ClientId, reconnect_token: str):
+    room = rooms.get(room_id)
+
+    if room is None:
+        return
+
+    room_client = room.clients.get(client_id)
+
+    if room_client is None or room_client.connected or room_client.reconnect_token != reconnect_token:
+        return
+
+    remove_from_room(room, client_id)
+
+
+def leave_rooms(client: Client, only_room_id: Optional[str] = None, dropped: bool = False):
+    for room_id, room in list(rooms.items()):
+        if client.id not in room.clients:
+            continue
+
+        if only_room_id is not None and room_id != only_room_id:
+            continue
+
+        # A client whose connection dropped keeps its slot for a while so it can rejoin
+        if dropped and client.id != room.host_id:
+            room_client = room.clients[client.id]
+            room_client.connected = False
+
+            Timer(
+                RECONNECT_GRACE,
+                expire_slot,
+                args=(room_id, client.id, room_client.reconnect_token),
+            ).start()
+
+            print(f"Client {client.id} lost connection to room {room_id}")
+            continue
+
+        remove_from_room(room, client.id)
+
+
+def relay(request: RelayMessage, client: Client):
+    # Only pass messages between members of the same room
+    if not any(
+        client.id in room.clients and request.client_id in room.clients
+        for room in rooms.values()
+    ):
+        return
+
+    if request.client_id not in clients:
+        return
+
+    clients[request.client_id].tx.send(asdict(Message(
+        type=RelayMessage.TYPE,
+        data=RelayMessage(
+            client_id=client.id,
+            payload=request.payload,
+        ),
+    )))
+
+
 def process_request(data: dict, client: Client):
     msg = from_dict(data_class=Message, data=data)
     client = ClientIndex(client.id)
@@ -227,26 +523,62 @@ def process_request(data: dict, client: Client):
         case "room/join":
             msg = from_dict(data_class=JoinRoomRequest, data=msg.data)
             join_room(msg, client())
+        case "room/list":
+            list_rooms(client())
+        case "room/leave":
+            msg = from_dict(data_class=LeaveRoomRequest, data=msg.data)
+            leave_rooms(client(), msg.room_id)
+        case "room/rejoin":
+            msg = from_dict(data_class=RejoinRoomRequest, data=msg.data)
+            rejoin_room(msg, client())
+        case "relay/send":
+            msg = from_dict(data_class=RelayMessage, data=msg.data)
+            relay(msg, client())
 
 
 def client_outbound(connection: socket.socket, rx: Connection):
     while True:
-        data = rx.recv()
+        try:
+            data = rx.recv()
+        except EOFError:
+            # The client has gone and its end of the pipe was closed
+            break
 
+        # Messages are separated by newlines so several can arrive in one read
         if type(data) is dict:
-            data = json.dumps(data).encode("utf-8")
+            data = (json.dumps(data) + "\n").encode("utf-8")
         elif type(data) is str:
-            data = data.encode("utf-8")
+            data = (data + "\n").encode("utf-8")
 
-        bytes_sent = connection.send(data)
+        connection.sendall(data)
 
 
 def client_inbound(connection: socket.socket, client: Client, max_buffer_size=4096):
+    buffer = b""
+
     while True:
         data = connection.recv(max_buffer_size)
-        data = json.loads(data)
 
-        process_request(data, client)
+        if not data:
+            break
+
+        buffer += data
+        *lines, buffer = buffer.split(b"\n")
+
+        for line in lines:
+            if not line.strip():
+                continue
+
+            try:
+                process_request(json.loads(line), client)
+            except Exception:
+                logging.error(f"Failed to process a request from client {client.id}")
+                traceback.print_exc()
+
+    # A newer connection may already have taken over this client's id by rejoining
+    if client.id in clients and clients[client.id] is client:
+        leave_rooms(client, dropped=True)
+        del clients[client.id]
 
 
 def client_handler(connection: socket.socket, ip: str, port: int, max_buffer_size=4096):
@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 
 use sdl2::pixels::Color;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::util::{Rect, Shape2D, Vec2};
 use specs::{Component, VecStorage};
@@ -11,19 +12,19 @@ impl Component for Position {
     type Storage = VecStorage<Self>;
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Velocity(pub Vec2);
 impl Component for Velocity {
     type Storage = VecStorage<Self>;
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Acceleration(pub Vec2);
 impl Component for Acceleration {
     type Storage = VecStorage<Self>;
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Copy, Clone)]
 pub struct Grounded(pub bool);
 impl Component for Grounded {
     type Storage = VecStorage<Self>;
@@ -57,6 +58,53 @@ impl Component for PlayerController {
     type Storage = VecStorage<Self>;
 }
 
+/// The name a player chose, drawn above them.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PlayerName(pub String);
+impl Component for PlayerName {
+    type Storage = VecStorage<Self>;
+}
+
+pub const EMOTE_DURATION: f64 = 3.0;
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub enum Emote {
+    Wave,
+    Yes,
+    No,
+    Help,
+}
+impl Emote {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Wave => "Hi!",
+            Self::Yes => "Yes",
+            Self::No => "No",
+            Self::Help => "Help!",
+        }
+    }
+}
+
+/// An emote shown above a player for a few seconds.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Emoting {
+    pub emote: Emote,
+    pub started_at: f64,
+}
+impl Component for Emoting {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub left: bool,
+    pub right: bool,
+    pub jump: bool,
+}
+impl Component for PlayerInput {
+    type Storage = VecStorage<Self>;
+}
+
 pub struct Collider<'a> {
     shape: Box<dyn Shape2D + Send + Sync + 'a>,
 }
@@ -1,30 +1,59 @@
+use std::fs::OpenOptions;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use sdl2::pixels::Color;
 use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio::time;
 
 use crate::components::{
-    Acceleration, Collider, FloorCollider, FloorCollision, Grounded, PlayerController,
-    RenderDescriptor, Velocity,
+    Collider, FloorCollider, FloorCollision, Grounded, PlayerController, PlayerName,
+    RenderDescriptor,
 };
-use crate::networking::components::{NetworkHandler, NetworkRecv, NetworkSend};
+use crate::networking::authority::AuthorityHandler;
+use crate::networking::chat::ChatHandler;
+use crate::networking::components::{NetworkHandler, NetworkSend};
+use crate::networking::interpolation::{InterpolationSettings, SnapshotInterpolationSystem};
+use crate::networking::lockstep::{
+    DesyncDetector, Lockstep, LockstepControlled, LockstepHandler, LockstepInputSystem,
+    LockstepPlayer,
+};
+use crate::networking::prediction::{self, Predicted};
+use crate::networking::replication::{
+    ComponentReplicator, ReplicationDirection, ReplicationPriority,
+};
+use crate::networking::spawning::SpawnHandler;
 use crate::networking::systems::{Message, TransmissionNetworkPortal};
-use crate::resources::{GameCamera, GameState, SystemState};
+use crate::networking::transport::{PortalTransport, Transport};
+use crate::replay::Recorder;
+use crate::resources::{GameCamera, GameState, Lobby, LocalPlayer, NetworkStats, SystemState};
 use crate::systems::{
-    EntityMovementSystem, EventSystem, FloorColliderSystem, PlayerMovementSystem,
+    BoxSpawnSystem, EntityMovementSystem, EventSystem, FloorColliderSystem, KillPlaneSystem,
+    PlayerInputSystem, PlayerMovementSystem, PushSystem,
 };
 use crate::util::{Rect, Vec2};
-use crate::NetworkMode;
 use crate::{components::Position, systems::RenderSystem, Args};
-use specs::{Builder, DispatcherBuilder, World, WorldExt};
+use crate::{prefabs, NetworkMode, SyncMode};
+use specs::{Builder, Dispatcher, DispatcherBuilder, RunNow, World, WorldExt};
+
+pub const TICK_RATE: u32 = 60;
+
+/// Advertised in the room list, as there is only the one level so far.
+pub const LEVEL_NAME: &str = "Proving Grounds";
 
 pub async fn game_main(
     args: Args,
     portal: Arc<Mutex<TransmissionNetworkPortal>>,
     channels: (broadcast::Sender<Message>, mpsc::Receiver<Message>),
 ) -> Result<(), String> {
+    // Only local input is recorded, which is not enough to replay anyone else
+    if args.record.is_some() && !matches!(args.networking, NetworkMode::None) {
+        return Err("Only games without networking can be recorded".to_string());
+    }
+    if let (SyncMode::Lockstep, NetworkMode::None) = (args.sync, args.networking) {
+        return Err("Lockstep needs someone to play against".to_string());
+    }
+
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
 
@@ -37,10 +66,7 @@ pub async fn game_main(
 
     let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
 
-    let mut world = World::new();
-
-    world.register::<FloorCollision>();
-    world.register::<FloorCollider>();
+    let network_id = portal.lock().await.client_id().unwrap_or(0) as u64;
 
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
@@ -48,87 +74,296 @@ pub async fn game_main(
 
     let event_pump = sdl_context.event_pump()?;
 
-    let mut dispatcher = DispatcherBuilder::new()
-        .with(PlayerMovementSystem {}, "sys_player_movement", &[])
+    let mut world = create_world(&args, args.networking)?;
+    let transport = PortalTransport::new(channels);
+    let (mut dispatcher, mut lockstep) = match args.sync {
+        SyncMode::State => (simulation(&args, transport, network_id), None),
+        SyncMode::Lockstep => (
+            lockstep_simulation(),
+            Some(LockstepHandler::new(transport, network_id as u32)),
+        ),
+    };
+    dispatcher.setup(&mut world);
+    if let Some(lockstep) = lockstep.as_mut() {
+        lockstep.setup(&mut world);
+    }
+
+    let mut events = EventSystem::new(event_pump, video_subsystem.text_input());
+    let mut render = RenderSystem::new(canvas);
+    events.setup(&mut world);
+    render.setup(&mut world);
+
+    if let NetworkMode::None = args.networking {
+        load_level(&mut world, args.networking, network_id, &args.name);
+    }
+
+    let mut recorder = match &args.record {
+        Some(path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / TICK_RATE as f64));
+
+    loop {
+        interval.tick().await;
+
+        // In lockstep the simulation, and the clock with it, stalls until everyone's inputs for
+        // the next tick have come in
+        let ticking = advance(&world, lockstep.as_mut());
+        if ticking {
+            start_tick(&world);
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.record(&world)?;
+            }
+            dispatcher.dispatch(&world);
+        }
+        events.run_now(&world);
+        render.run_now(&world);
+        prediction::reconcile(&mut world);
+        leave_lobby(&mut world, &args, network_id);
+
+        if let SystemState::Quit = world.read_resource::<GameState>().system_state {
+            break;
+        }
+        if ticking {
+            end_tick(&mut world);
+        }
+    }
+
+    Ok(())
+}
+
+/// Exchanges inputs with the other peers in lockstep, and says whether a tick can be simulated.
+fn advance(world: &World, lockstep: Option<&mut LockstepHandler>) -> bool {
+    match lockstep {
+        Some(lockstep) => {
+            lockstep.run_now(world);
+            world.read_resource::<Lockstep>().ready()
+        }
+        None => true,
+    }
+}
+
+/// The level is held back until the host starts it, and in lockstep the host also says who is
+/// playing.
+fn leave_lobby(world: &mut World, args: &Args, network_id: u64) {
+    if !matches!(
+        world.read_resource::<GameState>().system_state,
+        SystemState::Lobby
+    ) {
+        return;
+    }
+
+    let started = match args.sync {
+        SyncMode::State if world.read_resource::<Lobby>().started => {
+            load_level(world, args.networking, network_id, &args.name);
+            true
+        }
+        SyncMode::State => false,
+        SyncMode::Lockstep => {
+            let players = world.read_resource::<Lockstep>().players.clone();
+            if let Some(players) = &players {
+                load_lockstep_level(world, players, network_id as u32);
+            }
+            players.is_some()
+        }
+    };
+    if started {
+        world.write_resource::<GameState>().system_state = SystemState::Running;
+    }
+}
+
+/// Sets up a world with every resource the game needs, but none of the level.
+pub fn create_world(args: &Args, networking: NetworkMode) -> Result<World, String> {
+    let mut world = World::new();
+
+    world.register::<FloorCollision>();
+    world.register::<FloorCollider>();
+    // Only drawn, so nothing registers these when a replay runs without a window
+    world.register::<RenderDescriptor>();
+    world.register::<PlayerName>();
+
+    world.insert(GameState::new(match networking {
+        NetworkMode::None => SystemState::Running,
+        NetworkMode::Host | NetworkMode::Client => SystemState::Lobby,
+    }));
+    world.insert(networking);
+    world.insert(LocalPlayer {
+        name: args.name.clone(),
+    });
+    world.insert(Lobby {
+        capacity: args.max_players,
+        ..Lobby::default()
+    });
+    let log = match &args.stats_log {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Could not open {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    world.insert(NetworkStats {
+        log,
+        ..NetworkStats::default()
+    });
+    world.insert(InterpolationSettings::new(
+        args.interpolation_delay as f64 / 1000.0,
+    ));
+    world.insert(GameCamera::new(
+        (800, 600),
+        Vec2::new(0.0, 0.0),
+        Rect::new(Vec2::new(-16.0, 12.0), Vec2::new(16.0, -12.0)),
+    ));
+
+    Ok(world)
+}
+
+/// The systems that make up a tick of the game. Input and rendering are left to the caller, as
+/// they need the SDL context and a replay runs without them.
+pub fn simulation<'a, 'b>(
+    args: &Args,
+    transport: impl Transport + 'static,
+    network_id: u64,
+) -> Dispatcher<'a, 'b> {
+    DispatcherBuilder::new()
+        .with(PlayerInputSystem {}, "sys_player_input", &[])
+        .with(
+            PlayerMovementSystem {},
+            "sys_player_movement",
+            &["sys_player_input"],
+        )
         .with(EntityMovementSystem {}, "sys_entity_movement", &[])
         .with(
             FloorColliderSystem {},
             "sys_floor_collision",
             &["sys_entity_movement"],
         )
+        .with(PushSystem {}, "sys_push", &["sys_floor_collision"])
+        .with(BoxSpawnSystem {}, "sys_box_spawn", &["sys_floor_collision"])
+        .with(KillPlaneSystem {}, "sys_kill_plane", &["sys_box_spawn"])
         .with(
-            NetworkHandler::new(portal, channels),
+            NetworkHandler::new(transport)
+                .send_rate(args.send_rate)
+                .client_id(network_id as u32),
             "sys_network_handler",
-            &["sys_entity_movement"],
+            &[
+                "sys_entity_movement",
+                "sys_floor_collision",
+                "sys_push",
+                "sys_box_spawn",
+            ],
         )
-        .with_thread_local(EventSystem::new(event_pump))
-        .with_thread_local(RenderSystem::new(canvas))
-        .build();
+        .with(
+            SpawnHandler::new(network_id as u32),
+            "sys_spawn_handler",
+            &["sys_network_handler", "sys_kill_plane"],
+        )
+        .with(
+            AuthorityHandler::new(network_id as u32),
+            "sys_authority_handler",
+            &["sys_spawn_handler"],
+        )
+        .with(ChatHandler, "sys_chat_handler", &["sys_network_handler"])
+        .with(
+            ComponentReplicator::<Grounded>::new(
+                "grounded",
+                ReplicationDirection::HostToClients,
+                ReplicationPriority::Normal,
+            ),
+            "sys_replicate_grounded",
+            &["sys_network_handler"],
+        )
+        .with(
+            SnapshotInterpolationSystem {},
+            "sys_snapshot_interpolation",
+            &["sys_floor_collision", "sys_authority_handler"],
+        )
+        .build()
+}
 
-    dispatcher.setup(&mut world);
+/// In lockstep every peer runs the whole game from the same inputs, so nothing here may depend on
+/// anything but the inputs and the fixed timestep, and none of it talks to the network itself.
+pub fn lockstep_simulation<'a, 'b>() -> Dispatcher<'a, 'b> {
+    DispatcherBuilder::new()
+        .with(LockstepInputSystem {}, "sys_lockstep_input", &[])
+        .with(EntityMovementSystem {}, "sys_entity_movement", &[])
+        .with(
+            PlayerMovementSystem {},
+            "sys_player_movement",
+            &["sys_lockstep_input", "sys_entity_movement"],
+        )
+        .with(
+            FloorColliderSystem {},
+            "sys_floor_collision",
+            &["sys_player_movement"],
+        )
+        .with(
+            DesyncDetector {},
+            "sys_desync_detector",
+            &["sys_floor_collision"],
+        )
+        .build()
+}
 
-    world.insert(GameState::new(SystemState::Running));
-    world.insert(GameCamera::new(
-        (800, 600),
-        Vec2::new(0.0, 0.0),
-        Rect::new(Vec2::new(-16.0, 12.0), Vec2::new(16.0, -12.0)),
-    ));
+/// Moves the clock on by a tick, before the systems run.
+pub fn start_tick(world: &World) {
+    let mut game_state = world.write_resource::<GameState>();
+    game_state.delta_t = 1.0 / TICK_RATE as f32;
+    game_state.elapsed += 1.0 / TICK_RATE as f64;
+}
 
-    const PLAYER_WIDTH: f32 = 1.0;
-    const PLAYER_HEIGHT: f32 = 1.0;
+/// Finishes off a tick once every system has had its turn.
+pub fn end_tick(world: &mut World) {
+    world.write_resource::<GameState>().tick += 1;
+    world.maintain();
+}
 
-    const ACCELERATION_DUE_TO_GRAVITY: f32 = -130.0;
+/// Every peer builds the same level with the same players in the same order, so that their entities
+/// line up without anything having to be sent.
+fn load_lockstep_level(world: &mut World, players: &[LockstepPlayer], client_id: u32) {
+    load_terrain(world);
 
-    world
-        .create_entity()
-        .with(Position(Vec2::new(0.0, 0.0)))
-        .with(Velocity(Vec2::new(0.0, 0.0)))
-        .with(Acceleration(Vec2::new(0.0, ACCELERATION_DUE_TO_GRAVITY)))
-        .with(RenderDescriptor::new(
-            Rect::from_centre(Vec2::new(0.0, 0.0), PLAYER_WIDTH, PLAYER_HEIGHT),
-            Color::RGB(255, 0, 0),
-        ))
-        .with(Grounded(true))
-        .with(PlayerController {})
-        .with(Collider::new(Rect::from_centre(
-            Vec2::new(0.0, 0.0),
-            PLAYER_WIDTH,
-            PLAYER_HEIGHT,
-        )))
-        .with(FloorCollision {})
-        .with(NetworkSend::new(match args.networking {
-            NetworkMode::None | NetworkMode::Host => 0,
-            NetworkMode::Client => 1,
-        }))
-        .build();
+    for (i, player) in players.iter().enumerate() {
+        let colour = if player.client_id == client_id {
+            prefabs::LOCAL_PLAYER_COLOUR
+        } else {
+            prefabs::REMOTE_PLAYER_COLOUR
+        };
 
-    match args.networking {
-        NetworkMode::Host | NetworkMode::Client => {
-            let network = world
-                .create_entity()
-                .with(Position(Vec2::new(0.0, 0.0)))
-                .with(Velocity(Vec2::new(0.0, 0.0)))
-                .with(Acceleration(Vec2::new(0.0, ACCELERATION_DUE_TO_GRAVITY)))
-                .with(RenderDescriptor::new(
-                    Rect::from_centre(Vec2::new(0.0, 0.0), PLAYER_WIDTH, PLAYER_HEIGHT),
-                    Color::RGB(0, 0, 255),
-                ))
-                .with(Collider::new(Rect::from_centre(
-                    Vec2::new(0.0, 0.0),
-                    PLAYER_WIDTH,
-                    PLAYER_HEIGHT,
-                )))
-                .with(FloorCollision {})
-                .with(NetworkRecv::new(match args.networking {
-                    NetworkMode::Host => 1,
-                    NetworkMode::Client => 0,
-                    NetworkMode::None => panic!("Should not reach here"),
-                }))
-                .build();
-        }
-        _ => {}
+        prefabs::controlled_player(
+            world.create_entity(),
+            Vec2::new(i as f32 * 2.0 - (players.len() - 1) as f32, 0.0),
+            colour,
+        )
+        .with(PlayerName(player.name.clone()))
+        .with(LockstepControlled {
+            client_id: player.client_id,
+        })
+        .build();
     }
+}
+
+pub fn load_level(world: &mut World, networking: NetworkMode, network_id: u64, name: &str) {
+    let player = prefabs::controlled_player(
+        world.create_entity(),
+        Vec2::new(0.0, 0.0),
+        prefabs::LOCAL_PLAYER_COLOUR,
+    )
+    .with(PlayerController {})
+    .with(PlayerName(name.to_string()));
 
+    match networking {
+        NetworkMode::None | NetworkMode::Host => player.with(NetworkSend::new(network_id)).build(),
+        NetworkMode::Client => player.with(Predicted::new(network_id)).build(),
+    };
+
+    load_terrain(world);
+}
+
+fn load_terrain(world: &mut World) {
     world
         .create_entity()
         .with(Position(Vec2::new(-16.0, -10.0)))
@@ -188,29 +423,471 @@ pub async fn game_main(
         )))
         .with(FloorCollider {})
         .build();
+}
 
-    let mut start = Instant::now();
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
 
-    loop {
-        let now = Instant::now();
-        let delta_t = (now - start).as_secs_f32();
-        start = now;
+    use clap::Parser;
+    use sdl2::keyboard::Keycode;
+    use specs::{Join, WorldExt};
 
-        {
-            let mut game_state = world.write_resource::<GameState>();
-            game_state.delta_t = delta_t;
+    use super::*;
+    use crate::components::{Acceleration, Velocity};
+    use crate::networking::authority::{Authority, AuthorityRequest};
+    use crate::networking::components::{NetworkRecv, UpdateEntity};
+    use crate::networking::lockstep::INPUT_DELAY;
+    use crate::networking::prediction::RemoteControlled;
+    use crate::networking::systems::{ConnectionStatus, PeerJoined, ReliableMessage, MAX_DATAGRAM};
+    use crate::networking::transport::LoopbackNetwork;
+
+    /// One peer's side of a game, run without a window.
+    struct Peer<'a, 'b> {
+        args: Args,
+        world: World,
+        dispatcher: Dispatcher<'a, 'b>,
+        lockstep: Option<LockstepHandler>,
+        network_id: u64,
+    }
+    impl Peer<'_, '_> {
+        /// Sets up a peer as `game_main` would if run with `args`.
+        fn new(args: &[&str], network_id: u64, transport: impl Transport + 'static) -> Self {
+            let args = Args::parse_from(["test"].iter().chain(args));
+            let mut world = create_world(&args, args.networking).unwrap();
+            let (mut dispatcher, mut lockstep) = match args.sync {
+                SyncMode::State => (simulation(&args, transport, network_id), None),
+                SyncMode::Lockstep => (
+                    lockstep_simulation(),
+                    Some(LockstepHandler::new(transport, network_id as u32)),
+                ),
+            };
+            dispatcher.setup(&mut world);
+            if let Some(lockstep) = lockstep.as_mut() {
+                lockstep.setup(&mut world);
+            }
+            // Left to the systems that draw the game, which are not run here
+            world.register::<Predicted>();
+
+            Self {
+                args,
+                world,
+                dispatcher,
+                lockstep,
+                network_id,
+            }
         }
 
-        dispatcher.dispatch(&mut world);
-        {
-            let game_state = world.read_resource::<GameState>();
-            if let SystemState::Quit = game_state.system_state {
-                break;
+        /// Runs a tick the way `game_main` does, with `keys` held down.
+        fn tick(&mut self, keys: &[Keycode]) {
+            {
+                let keys = keys.iter().copied().collect::<HashSet<_>>();
+                let mut game_state = self.world.write_resource::<GameState>();
+                game_state.keys_pressed = &keys - &game_state.keys_held;
+                game_state.keys_released = &game_state.keys_held - &keys;
+                game_state.keys_held = keys;
             }
+
+            let ticking = advance(&self.world, self.lockstep.as_mut());
+            if ticking {
+                start_tick(&self.world);
+                self.dispatcher.dispatch(&self.world);
+            }
+            prediction::reconcile(&mut self.world);
+            leave_lobby(&mut self.world, &self.args, self.network_id);
+            if ticking {
+                end_tick(&mut self.world);
+            }
+        }
+
+        fn running(&self) -> bool {
+            matches!(
+                self.world.read_resource::<GameState>().system_state,
+                SystemState::Running
+            )
+        }
+
+        /// Where this peer has the local player.
+        fn own_position(&self) -> Vec2 {
+            let position = self.world.read_storage::<Position>();
+            let controller = self.world.read_storage::<PlayerController>();
+
+            (&position, &controller).join().next().unwrap().0 .0
+        }
+
+        /// Where this peer has someone else's entity, and what it is called.
+        fn following(&self, network_id: u64) -> Option<(Vec2, String)> {
+            let network_recv = self.world.read_storage::<NetworkRecv>();
+            let position = self.world.read_storage::<Position>();
+            let name = self.world.read_storage::<PlayerName>();
+
+            (&network_recv, &position, &name)
+                .join()
+                .find(|c| c.0.network_id() == network_id)
+                .map(|(_, position, name)| (position.0, name.0.clone()))
+        }
+
+        fn remote_players(&self) -> Vec<u64> {
+            (&self.world.read_storage::<RemoteControlled>())
+                .join()
+                .map(|c| c.network_id())
+                .collect()
         }
-        world.maintain();
-        time::sleep(Duration::new(0, 1_000_000_000u32 / 60));
     }
 
-    Ok(())
+    #[test]
+    fn a_client_follows_the_host_until_it_leaves() {
+        let (network, transport) = LoopbackNetwork::new(1);
+        let mut host = Peer::new(
+            &[
+                "--networking",
+                "host",
+                "--name",
+                "Host",
+                "--max-players",
+                "2",
+            ],
+            1,
+            transport,
+        );
+        let mut client = Peer::new(
+            &["--networking", "client", "--name", "Client"],
+            2,
+            network.join(2, "Client"),
+        );
+
+        // The room holds two, so the host starts as soon as the client is in
+        for _ in 0..10 {
+            host.tick(&[]);
+            client.tick(&[]);
+        }
+        assert!(host.running());
+        assert!(client.running());
+
+        // The host spawns a player for the client to drive, and the roster gives the client one
+        // for the host
+        assert_eq!(host.remote_players(), vec![2]);
+        let (start, name) = client
+            .following(1)
+            .expect("the host's player was not spawned");
+        assert_eq!(name, "Host");
+
+        for _ in 0..60 {
+            host.tick(&[Keycode::D]);
+            client.tick(&[]);
+        }
+        for _ in 0..30 {
+            host.tick(&[]);
+            client.tick(&[]);
+        }
+        let (end, _) = client.following(1).unwrap();
+        assert!(
+            end.x > start.x + 1.0,
+            "{} did not move on from {}",
+            end.x,
+            start.x
+        );
+        assert!((end - host.own_position()).magnitude() < 0.01);
+
+        network.leave(2);
+        host.tick(&[]);
+        host.tick(&[]);
+        assert!(host.remote_players().is_empty());
+    }
+
+    #[test]
+    fn a_box_moved_impossibly_is_taken_back_by_the_host() {
+        let (network, transport) = LoopbackNetwork::new(1);
+        let mut host = Peer::new(
+            &[
+                "--networking",
+                "host",
+                "--name",
+                "Host",
+                "--max-players",
+                "2",
+            ],
+            1,
+            transport,
+        );
+        // The client is played by hand, so it can send whatever it likes
+        let mut client = network.join(2, "Client");
+
+        for _ in 0..10 {
+            host.tick(&[]);
+        }
+        host.tick(&[Keycode::B]);
+        host.tick(&[]);
+
+        let network_id = u32::MAX as u64 + 1;
+        let owner = |host: &Peer| {
+            let network_send = host.world.read_storage::<NetworkSend>();
+            let authority = host.world.read_storage::<Authority>();
+
+            (&network_send, &authority)
+                .join()
+                .find(|c| c.0.network_id() == network_id)
+                .expect("the box was not spawned")
+                .1
+                .owner()
+        };
+        let update = |host: &Peer, offset: Vec2| {
+            let network_send = host.world.read_storage::<NetworkSend>();
+            let position = host.world.read_storage::<Position>();
+            let velocity = host.world.read_storage::<Velocity>();
+            let acceleration = host.world.read_storage::<Acceleration>();
+
+            let (_, position, velocity, acceleration) =
+                (&network_send, &position, &velocity, &acceleration)
+                    .join()
+                    .find(|c| c.0.network_id() == network_id)
+                    .unwrap();
+            let update = UpdateEntity::new(
+                network_id,
+                host.world.read_resource::<GameState>().elapsed,
+                position.0 + offset,
+                velocity.0,
+                acceleration.0,
+            );
+            Message::new("entity/update".to_string(), update)
+        };
+
+        client.send(Message::new(
+            "authority/request".to_string(),
+            AuthorityRequest { network_id },
+        ));
+        host.tick(&[]);
+        assert_eq!(owner(&host), 2);
+
+        client.send(update(&host, Vec2::new(0.0, 0.0)));
+        host.tick(&[]);
+        assert_eq!(owner(&host), 2);
+
+        client.send(update(&host, Vec2::new(100.0, 0.0)));
+        host.tick(&[]);
+        assert_eq!(owner(&host), 1);
+        // The grant goes out with the host's next tick
+        host.tick(&[]);
+
+        let mut taken_back = false;
+        while let Some(msg) = client.try_recv() {
+            taken_back |= msg.msg_type == "authority/grant" && msg.data["owner"] == 1;
+        }
+        assert!(
+            taken_back,
+            "the client was not told the host took the box back"
+        );
+    }
+
+    fn lockstep_peers(
+        max_players: &str,
+    ) -> (
+        LoopbackNetwork,
+        Peer<'static, 'static>,
+        Peer<'static, 'static>,
+    ) {
+        let (network, transport) = LoopbackNetwork::new(1);
+        let host = Peer::new(
+            &[
+                "--networking",
+                "host",
+                "--sync",
+                "lockstep",
+                "--max-players",
+                max_players,
+            ],
+            1,
+            transport,
+        );
+        let client = Peer::new(
+            &["--networking", "client", "--sync", "lockstep"],
+            2,
+            network.join(2, "Client"),
+        );
+        (network, host, client)
+    }
+
+    #[test]
+    fn lockstep_waits_for_the_host_to_start() {
+        let (_network, mut host, mut client) = lockstep_peers("3");
+
+        // There is room for one more, so the host keeps waiting
+        for _ in 0..30 {
+            host.tick(&[]);
+            client.tick(&[]);
+        }
+        assert!(!host.running());
+        assert!(!client.running());
+        assert!(client.world.read_resource::<Lockstep>().players.is_none());
+
+        host.world.write_resource::<Lobby>().start_requested = true;
+        for _ in 0..2 {
+            host.tick(&[]);
+            client.tick(&[]);
+        }
+        assert!(host.running());
+        assert!(client.running());
+        let players = client.world.read_resource::<Lockstep>().players.clone();
+        assert_eq!(players.map(|players| players.len()), Some(2));
+    }
+
+    #[test]
+    fn lockstep_peers_stay_in_step_until_one_drifts() {
+        let (_network, mut host, mut client) = lockstep_peers("2");
+
+        for i in 0..300 {
+            let host_keys: &[Keycode] = match i % 50 {
+                0..=19 => &[Keycode::D],
+                25 => &[Keycode::B],
+                _ => &[],
+            };
+            let client_keys: &[Keycode] = match i % 40 {
+                0..=9 => &[Keycode::A, Keycode::W],
+                30 => &[Keycode::B],
+                _ => &[],
+            };
+            host.tick(host_keys);
+            client.tick(client_keys);
+        }
+        assert!(host.world.read_resource::<Lockstep>().tick > 200);
+        assert!(host.world.read_resource::<Lockstep>().desync.is_none());
+        // The clock only moves on with the simulation, however often it stalls
+        for peer in [&host, &client] {
+            assert_eq!(
+                peer.world.read_resource::<GameState>().tick,
+                peer.world.read_resource::<Lockstep>().tick
+            );
+        }
+
+        // Nudging the client's world makes every hash from the next tick on differ from the host's
+        let drifted_on = client.world.read_resource::<Lockstep>().tick;
+        for (_, position) in (
+            &client.world.read_storage::<LockstepControlled>(),
+            &mut client.world.write_storage::<Position>(),
+        )
+            .join()
+        {
+            position.0.x += 0.001;
+        }
+        for _ in 0..60 {
+            host.tick(&[]);
+            client.tick(&[]);
+        }
+
+        let desync = host.world.read_resource::<Lockstep>().desync.unwrap();
+        assert_eq!((desync.tick, desync.client_id), (drifted_on, 2));
+        let told = client.world.read_resource::<Lockstep>().desync;
+        assert_eq!(told.map(|desync| desync.tick), Some(drifted_on));
+    }
+
+    #[test]
+    fn lockstep_goes_on_without_a_client_that_stops_sending() {
+        let (_network, mut host, mut client) = lockstep_peers("2");
+
+        for _ in 0..30 {
+            host.tick(&[]);
+            client.tick(&[]);
+        }
+
+        // Only the client's input that is already on its way can be played
+        let stopped_on = host.world.read_resource::<Lockstep>().tick;
+        for _ in 0..2 * TICK_RATE {
+            host.tick(&[]);
+        }
+        let stalled_on = host.world.read_resource::<Lockstep>().tick;
+        assert!(stalled_on <= stopped_on + INPUT_DELAY + 1);
+
+        for _ in 0..2 * TICK_RATE {
+            host.tick(&[]);
+        }
+        assert!(host.world.read_resource::<Lockstep>().tick > stalled_on + TICK_RATE as u64 / 2);
+    }
+
+    #[test]
+    fn a_lockstep_client_is_told_the_host_left() {
+        let (network, mut host, mut client) = lockstep_peers("2");
+
+        for _ in 0..30 {
+            host.tick(&[]);
+            client.tick(&[]);
+        }
+        assert!(client.world.read_resource::<Lockstep>().ended.is_none());
+
+        // What the portal raises when the host's connection goes
+        network.notify(2, "room/closed", 1);
+        network.notify(
+            2,
+            "connection/status",
+            ConnectionStatus::Lost("The host left the room".to_string()),
+        );
+        client.tick(&[]);
+
+        let ended = client.world.read_resource::<Lockstep>().ended.clone();
+        assert_eq!(ended.as_deref(), Some("The host left the room"));
+    }
+
+    #[test]
+    fn a_rejoin_snapshot_fits_in_datagrams() {
+        let (network, transport) = LoopbackNetwork::new(1);
+        let mut host = Peer::new(
+            &[
+                "--networking",
+                "host",
+                "--name",
+                "Host",
+                "--max-players",
+                "2",
+            ],
+            1,
+            transport,
+        );
+        let mut client = network.join(2, "Client");
+
+        for _ in 0..10 {
+            host.tick(&[]);
+        }
+        for _ in 0..20 {
+            host.tick(&[Keycode::B]);
+            host.tick(&[]);
+        }
+        while client.try_recv().is_some() {}
+
+        network.notify(
+            1,
+            "peer/rejoin",
+            PeerJoined {
+                client_id: 2,
+                name: "Client".to_string(),
+            },
+        );
+        // Sent on the next tick, and handed to the transport on the one after
+        for _ in 0..3 {
+            host.tick(&[]);
+        }
+
+        let mut parts = 0;
+        while let Some(msg) = client.try_recv() {
+            if msg.msg_type == "room/snapshot" {
+                parts += 1;
+            }
+
+            // Sent as it would be to a peer, wrapped up to be resent and then encrypted
+            let wrapped = Message::new(
+                "reliable/message".to_string(),
+                ReliableMessage {
+                    seq: u64::MAX,
+                    message: msg.clone(),
+                },
+            );
+            // The packet counter and authentication tag come on top
+            let size = serde_json::to_string(&wrapped).unwrap().len() + 8 + 16;
+            assert!(
+                size <= MAX_DATAGRAM,
+                "a {} message of {} bytes is too big",
+                msg.msg_type,
+                size
+            );
+        }
+        assert_eq!(parts, 21);
+    }
 }
@@ -5,17 +5,23 @@ mod util;
 // mod gjk; // Not working
 mod game;
 mod networking;
+mod prefabs;
+mod replay;
 mod sat;
+mod text;
 
 extern crate sdl2;
 extern crate serde;
 extern crate specs;
 
+use crate::networking::conditions::NetworkConditions;
 use crate::networking::systems::{Message, TransmissionNetworkPortal};
 use clap::{ArgEnum, Parser};
 use std::{
     net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 use tokio::sync::{broadcast, mpsc, Mutex};
 
@@ -26,6 +32,14 @@ pub enum NetworkMode {
     Client,
 }
 
+/// How peers keep their worlds the same: by the host sending everyone the state of each entity,
+/// or by everyone sending only their inputs and each simulating the whole game.
+#[derive(Copy, Clone, ArgEnum, Debug)]
+pub enum SyncMode {
+    State,
+    Lockstep,
+}
+
 #[derive(Parser, Debug, Clone)]
 pub struct Args {
     #[clap(arg_enum, short, long, default_value = "none")]
@@ -34,26 +48,116 @@ pub struct Args {
     #[clap(short, long, default_value = "127.0.0.1")]
     pub source: IpAddr,
 
-    #[clap(long, default_value = "0")]
-    pub send_port: u16,
-
-    #[clap(long, default_value = "0")]
-    pub recv_port: u16,
+    #[clap(short, long, default_value = "0")]
+    pub port: u16,
 
     #[clap(short, long, default_value = "127.0.0.1:50000")]
     pub rendezvous: String,
 
     #[clap(short = 'i', long)]
     pub room_id: Option<String>,
+
+    #[clap(long, default_value = "100")]
+    pub interpolation_delay: u64,
+
+    #[clap(short, long, default_value = "4")]
+    pub max_players: usize,
+
+    /// The name shown to other players
+    #[clap(long, default_value = "Player")]
+    pub name: String,
+
+    /// Ignore datagrams sent directly by peers, forcing traffic through the relay
+    #[clap(long)]
+    pub block_direct: bool,
+
+    /// Password needed to join the room, when hosting, or to get into it, when joining
+    #[clap(long)]
+    pub password: Option<String>,
+
+    /// Keep a hosted room out of the room list
+    #[clap(long)]
+    pub private: bool,
+
+    /// Delay every datagram sent to peers by this many milliseconds
+    #[clap(long, default_value = "0")]
+    pub latency: u64,
+
+    /// Delay datagrams by up to this many milliseconds more, chosen at random for each one
+    #[clap(long, default_value = "0")]
+    pub jitter: u64,
+
+    /// Percentage of datagrams to drop
+    #[clap(long, default_value = "0")]
+    pub packet_loss: f64,
+
+    /// Percentage of datagrams to send twice
+    #[clap(long, default_value = "0")]
+    pub duplicate: f64,
+
+    /// Percentage of datagrams to hold back so that later ones overtake them
+    #[clap(long, default_value = "0")]
+    pub reorder: f64,
+
+    /// Append each peer's network statistics to this file, once a second
+    #[clap(long)]
+    pub stats_log: Option<PathBuf>,
+
+    /// Send entity states from the host, or only inputs, with every peer simulating the game
+    #[clap(arg_enum, long, default_value = "state")]
+    pub sync: SyncMode,
+
+    /// How many times a second to send entity states to peers
+    #[clap(long, default_value = "30")]
+    pub send_rate: f64,
+
+    /// Record every tick of the session to this file, so that it can be replayed
+    #[clap(long)]
+    pub record: Option<PathBuf>,
+
+    /// Play back a recorded session instead of starting a new one
+    #[clap(long)]
+    pub replay: Option<PathBuf>,
+
+    /// Play the replay without a window, as fast as possible, checking it still matches the recording
+    #[clap(long, requires = "replay")]
+    pub headless: bool,
+
+    /// Print the public rooms on the rendezvous server and exit
+    #[clap(short, long)]
+    pub list_rooms: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), String> {
     let args = Args::parse();
 
-    println!("{:?}", args);
+    println!(
+        "{:?}",
+        Args {
+            password: args.password.as_ref().map(|_| "(hidden)".to_string()),
+            ..args.clone()
+        }
+    );
+
+    if let Some(path) = args.replay.clone() {
+        return replay::replay_main(args, &path).await;
+    }
 
-    let portal = TransmissionNetworkPortal::new();
+    let portal = TransmissionNetworkPortal::new()
+        .block_direct(args.block_direct)
+        .name(args.name.clone())
+        .conditions(NetworkConditions {
+            latency: Duration::from_millis(args.latency),
+            jitter: Duration::from_millis(args.jitter),
+            loss: probability("packet-loss", args.packet_loss)?,
+            duplicate: probability("duplicate", args.duplicate)?,
+            reorder: probability("reorder", args.reorder)?,
+        });
+
+    if args.list_rooms {
+        return list_rooms(portal, &args).await;
+    }
 
     let (portal, channels) = if let NetworkMode::None = args.networking {
         let (tx, _) = broadcast::channel::<Message>(1);
@@ -61,12 +165,7 @@ async fn main() -> Result<(), String> {
         (Arc::new(Mutex::new(portal)), (tx, rx))
     } else {
         let (portal, channels) = portal
-            .rendezvous_init(
-                args.rendezvous.clone(),
-                args.source,
-                args.send_port,
-                args.recv_port,
-            )
+            .rendezvous_init(args.rendezvous.clone(), args.source, args.port)
             .await
             .map_err(|e| e.to_string())?;
 
@@ -74,7 +173,12 @@ async fn main() -> Result<(), String> {
             NetworkMode::Host => portal
                 .lock()
                 .await
-                .create_room()
+                .create_room(
+                    args.max_players,
+                    game::LEVEL_NAME.to_string(),
+                    !args.private,
+                    args.password.clone(),
+                )
                 .await
                 .map_err(|e| e.to_string())?,
             NetworkMode::Client => {
@@ -82,11 +186,11 @@ async fn main() -> Result<(), String> {
                     portal
                         .lock()
                         .await
-                        .join_room(id.clone())
+                        .join_room(id.clone(), args.password.clone())
                         .await
                         .map_err(|e| e.to_string())?
                 } else {
-                    panic!("Failed to supply a room id!")
+                    return Err("Failed to supply a room id!".to_string());
                 }
             }
             _ => {}
@@ -95,5 +199,64 @@ async fn main() -> Result<(), String> {
         (portal, channels)
     };
 
-    game::game_main(args, portal, channels).await
+    let result = game::game_main(args, portal.clone(), channels).await;
+
+    portal
+        .lock()
+        .await
+        .leave_room()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    result
+}
+
+async fn list_rooms(portal: TransmissionNetworkPortal, args: &Args) -> Result<(), String> {
+    let (portal, _channels) = portal
+        .rendezvous_init(args.rendezvous.clone(), args.source, args.port)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let rooms = portal
+        .lock()
+        .await
+        .list_rooms()
+        .await
+        .map_err(|e| e.to_string())?;
+    let rooms = rooms
+        .await
+        .map_err(|_| "The rendezvous server did not send a room list".to_string())?;
+
+    if rooms.is_empty() {
+        println!("There are no public rooms");
+    }
+
+    for room in rooms {
+        println!(
+            "{}  {}/{} players  {}{}",
+            room.room_id,
+            room.players,
+            room.max_clients,
+            room.level,
+            if room.has_password {
+                "  (password)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(())
+}
+
+/// Turns one of the percentage flags into a probability.
+fn probability(flag: &str, percentage: f64) -> Result<f64, String> {
+    if (0.0..=100.0).contains(&percentage) {
+        Ok(percentage / 100.0)
+    } else {
+        Err(format!(
+            "--{} must be a percentage between 0 and 100, not {}",
+            flag, percentage
+        ))
+    }
 }
@@ -0,0 +1,417 @@
+use serde_derive::{Deserialize, Serialize};
+use specs::{
+    Component, Entities, Entity, Join, Read, ReadExpect, ReadStorage, System, VecStorage, Write,
+    WriteStorage,
+};
+
+use crate::{
+    components::{Collider, FloorCollider, Position},
+    resources::GameState,
+    NetworkMode,
+};
+
+use super::components::{
+    decode, network_id_of, NetworkInbox, NetworkRecv, NetworkSend, UpdateEntity,
+};
+use super::interpolation::{Snapshot, SnapshotBuffer};
+use super::systems::{Message, PeerEvent};
+use super::validation::{MovementLimits, MovementValidator};
+
+/// Which peer simulates a networked entity that can change hands, like a box being pushed.
+/// Everyone else follows the owner's updates, passed on by the host.
+#[derive(Debug)]
+pub struct Authority {
+    owner: u32,
+    local: bool,
+    touched_at: Option<f64>,
+    requested_at: Option<f64>,
+}
+impl Authority {
+    pub fn new(owner: u32, local_id: u32) -> Self {
+        Self {
+            owner,
+            local: owner == local_id,
+            touched_at: None,
+            requested_at: None,
+        }
+    }
+
+    pub fn owner(&self) -> u32 {
+        self.owner
+    }
+
+    /// Whether this peer simulates the entity.
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    pub fn is_owner(&self, sender: Option<u32>) -> bool {
+        sender == Some(self.owner)
+    }
+
+    /// Called when the local player interacts with the entity, so this peer asks for authority
+    /// over it, or keeps hold of it.
+    pub fn touch(&mut self, elapsed: f64) {
+        self.touched_at = Some(elapsed);
+    }
+
+    fn hand_to(&mut self, owner: u32, local_id: u32) {
+        self.owner = owner;
+        self.local = owner == local_id;
+        self.requested_at = None;
+    }
+
+    fn touched_within(&self, elapsed: f64, seconds: f64) -> bool {
+        self.touched_at
+            .is_some_and(|touched_at| elapsed - touched_at < seconds)
+    }
+}
+impl Component for Authority {
+    type Storage = VecStorage<Self>;
+}
+
+/// Asks the host for authority over an entity, or gives it back.
+#[derive(Serialize, Deserialize)]
+pub struct AuthorityRequest {
+    pub network_id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthorityGrant {
+    pub network_id: u64,
+    pub owner: u32,
+}
+
+/// Owners simulate the entity themselves, so only everyone else follows its snapshots. Those
+/// snapshots are checked against what the new owner could really have done, starting afresh from
+/// the handoff.
+pub(super) fn set_owner(
+    entity: Entity,
+    owner: u32,
+    local_id: u32,
+    authority: &mut WriteStorage<Authority>,
+    snapshot_buffer: &mut WriteStorage<SnapshotBuffer>,
+    movement_validator: &mut WriteStorage<MovementValidator>,
+) {
+    let authority = match authority.get_mut(entity) {
+        Some(authority) => authority,
+        None => return,
+    };
+    authority.hand_to(owner, local_id);
+
+    if authority.is_local() {
+        snapshot_buffer.remove(entity);
+        movement_validator.remove(entity);
+        return;
+    }
+
+    if !snapshot_buffer.contains(entity) {
+        snapshot_buffer
+            .insert(entity, SnapshotBuffer::new())
+            .unwrap_or_else(|e| {
+                println!("{}", e);
+                None
+            });
+    }
+    movement_validator
+        .insert(entity, MovementValidator::default())
+        .unwrap_or_else(|e| {
+            println!("{}", e);
+            None
+        });
+}
+
+/// Hands entities between peers as they interact with them, and follows the updates their owners
+/// send, which the host checks before passing them on.
+pub struct AuthorityHandler {
+    limits: MovementLimits,
+    client_id: u32,
+}
+impl AuthorityHandler {
+    pub fn new(client_id: u32) -> Self {
+        Self {
+            limits: MovementLimits::default(),
+            client_id,
+        }
+    }
+}
+impl AuthorityHandler {
+    /// How long after the local player last touched an entity it still counts as interacting.
+    const INTERACTION_TIMEOUT: f64 = 1.0;
+    /// How often an unanswered request for authority is repeated.
+    const REQUEST_INTERVAL: f64 = 0.5;
+
+    /// Only the host decides who owns what, and sends everyone the grant this returns.
+    fn grant_authority(
+        &self,
+        entity: Entity,
+        network_id: u64,
+        owner: u32,
+        authority: &mut WriteStorage<Authority>,
+        snapshot_buffer: &mut WriteStorage<SnapshotBuffer>,
+        movement_validator: &mut WriteStorage<MovementValidator>,
+    ) -> Message {
+        set_owner(
+            entity,
+            owner,
+            self.client_id,
+            authority,
+            snapshot_buffer,
+            movement_validator,
+        );
+        Message::new(
+            "authority/grant".to_string(),
+            AuthorityGrant { network_id, owner },
+        )
+    }
+}
+impl<'a> System<'a> for AuthorityHandler {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, NetworkSend>,
+        ReadStorage<'a, NetworkRecv>,
+        WriteStorage<'a, Authority>,
+        WriteStorage<'a, SnapshotBuffer>,
+        WriteStorage<'a, MovementValidator>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Collider<'static>>,
+        ReadStorage<'a, FloorCollider>,
+        Read<'a, GameState>,
+        ReadExpect<'a, NetworkMode>,
+        Write<'a, NetworkInbox>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            network_send,
+            network_recv,
+            mut authority,
+            mut snapshot_buffer,
+            mut movement_validator,
+            position,
+            collider,
+            floor_collider,
+            game_state,
+            network_mode,
+            mut inbox,
+        ) = data;
+
+        let is_host = matches!(*network_mode, NetworkMode::Host);
+        let is_client = matches!(*network_mode, NetworkMode::Client);
+
+        // Whoever is pushing an entity should be simulating it, so clients ask for authority over
+        // what their player touches, and hand it back to the host once they let go. The host
+        // waits for its turn rather than taking entities from clients.
+        for (entity, authority) in (&entities, &mut authority).join() {
+            let network_id = match network_id_of(entity, &network_send, &network_recv) {
+                Some(network_id) => network_id,
+                None => continue,
+            };
+            let interacting =
+                authority.touched_within(game_state.elapsed, Self::INTERACTION_TIMEOUT);
+            let due = authority.requested_at.is_none_or(|requested_at| {
+                game_state.elapsed - requested_at >= Self::REQUEST_INTERVAL
+            });
+
+            if !is_client || !due || interacting == authority.is_local() {
+                continue;
+            }
+
+            authority.requested_at = Some(game_state.elapsed);
+            let msg_type = if interacting {
+                "authority/request"
+            } else {
+                "authority/release"
+            };
+            inbox.send(Message::new(
+                msg_type.to_string(),
+                AuthorityRequest { network_id },
+            ));
+        }
+
+        let floors = (&position, &collider, &floor_collider)
+            .join()
+            .map(|(position, collider, _)| (position.0, collider))
+            .collect::<Vec<_>>();
+
+        for msg in inbox.received(&[
+            "entity/update",
+            "authority/request",
+            "authority/release",
+            "authority/grant",
+            "peer/leave",
+        ]) {
+            let sender = msg.sender;
+
+            match msg.msg_type.as_str() {
+                "entity/update" => {
+                    let msg: UpdateEntity = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    let snapshot = Snapshot::new(
+                        msg.timestamp,
+                        msg.position.into(),
+                        msg.velocity.into(),
+                        msg.acceleration.into(),
+                    );
+
+                    let mut reclaimed = vec![];
+
+                    for (entity, snapshot_buffer, validator, collider, authority) in (
+                        &entities,
+                        &mut snapshot_buffer,
+                        (&mut movement_validator).maybe(),
+                        collider.maybe(),
+                        (&authority).maybe(),
+                    )
+                        .join()
+                        .filter(|c| {
+                            network_id_of(c.0, &network_send, &network_recv) == Some(msg.entity_id)
+                        })
+                    {
+                        if is_host {
+                            let result = match (authority, validator) {
+                                (Some(authority), _) if !authority.is_owner(sender) => {
+                                    Err("not the owner".to_string())
+                                }
+                                (_, Some(validator)) => validator
+                                    .validate(
+                                        &self.limits,
+                                        &snapshot,
+                                        game_state.elapsed,
+                                        collider,
+                                        &floors,
+                                    )
+                                    .map_err(|e| {
+                                        if authority.is_some() {
+                                            reclaimed.push(entity);
+                                        }
+                                        format!("{} ({} violations)", e, validator.violations)
+                                    }),
+                                (_, None) => Err("entity is not client owned".to_string()),
+                            };
+
+                            if let Err(e) = result {
+                                println!(
+                                    "Rejected update for entity {} from client {:?}: {}",
+                                    msg.entity_id, sender, e
+                                );
+                                continue;
+                            }
+                        }
+
+                        snapshot_buffer.push(snapshot, game_state.elapsed);
+                    }
+
+                    // The host takes back anything moved impossibly, so the owner is put right by
+                    // the host's state along with everyone else
+                    for entity in reclaimed {
+                        let grant = self.grant_authority(
+                            entity,
+                            msg.entity_id,
+                            self.client_id,
+                            &mut authority,
+                            &mut snapshot_buffer,
+                            &mut movement_validator,
+                        );
+                        inbox.send(grant);
+                    }
+                }
+                "authority/request" | "authority/release" if is_host => {
+                    let releasing = msg.msg_type == "authority/release";
+                    let msg: AuthorityRequest = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    let client_id = match sender {
+                        Some(sender) => sender,
+                        None => continue,
+                    };
+
+                    let entity = (&entities, &network_send, &authority)
+                        .join()
+                        .find(|c| c.1.network_id() == msg.network_id)
+                        .map(|c| {
+                            (
+                                c.0,
+                                c.2.owner(),
+                                c.2.touched_within(game_state.elapsed, Self::INTERACTION_TIMEOUT),
+                            )
+                        });
+                    let (entity, owner, host_interacting) = match entity {
+                        Some(entity) => entity,
+                        None => continue,
+                    };
+
+                    // A request is only granted while nobody else is interacting with the entity;
+                    // otherwise the client keeps asking until they are done
+                    let new_owner = if releasing && owner == client_id {
+                        self.client_id
+                    } else if !releasing && owner == self.client_id && !host_interacting {
+                        client_id
+                    } else {
+                        continue;
+                    };
+
+                    let grant = self.grant_authority(
+                        entity,
+                        msg.network_id,
+                        new_owner,
+                        &mut authority,
+                        &mut snapshot_buffer,
+                        &mut movement_validator,
+                    );
+                    inbox.send(grant);
+                }
+                "authority/grant" if !is_host => {
+                    let msg: AuthorityGrant = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    let entity = (&entities, &network_recv, &authority)
+                        .join()
+                        .find(|c| c.1.network_id() == msg.network_id)
+                        .map(|c| c.0);
+                    if let Some(entity) = entity {
+                        set_owner(
+                            entity,
+                            msg.owner,
+                            self.client_id,
+                            &mut authority,
+                            &mut snapshot_buffer,
+                            &mut movement_validator,
+                        );
+                    }
+                }
+                // Anything the peer that left was simulating goes back to the host
+                "peer/leave" if sender.is_none() && is_host => {
+                    let msg: PeerEvent = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    let abandoned = (&entities, &network_send, &authority)
+                        .join()
+                        .filter(|c| c.2.owner() == msg.client_id)
+                        .map(|c| (c.0, c.1.network_id()))
+                        .collect::<Vec<_>>();
+                    for (entity, network_id) in abandoned {
+                        let grant = self.grant_authority(
+                            entity,
+                            network_id,
+                            self.client_id,
+                            &mut authority,
+                            &mut snapshot_buffer,
+                            &mut movement_validator,
+                        );
+                        inbox.send(grant);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use serde_derive::{Deserialize, Serialize};
+use specs::{Entities, Join, Read, ReadExpect, ReadStorage, System, Write, WriteStorage};
+
+use crate::{
+    components::{Emote, Emoting, PlayerController, PlayerName, EMOTE_DURATION},
+    resources::{Chat, GameState, Gestures, LocalPlayer, Ping, MAX_CHAT_LENGTH, PING_DURATION},
+    NetworkMode,
+};
+
+use super::components::{decode, NetworkInbox, NetworkRecv, NetworkSend, Vec2ForSerde};
+use super::prediction::{Predicted, RemoteControlled};
+use super::systems::Message;
+
+#[derive(Serialize, Deserialize)]
+pub struct PingMessage {
+    pub name: String,
+    pub position: Vec2ForSerde,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmoteMessage {
+    pub network_id: u64,
+    pub emote: Emote,
+}
+
+/// Clients send chat to the host, which fills in who it came from and passes it on to everyone.
+#[derive(Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub name: String,
+    pub text: String,
+}
+
+/// Passes chat, pings and emotes between players. They all go through the host, which is the one
+/// that says who sent them.
+pub struct ChatHandler;
+impl<'a> System<'a> for ChatHandler {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, NetworkSend>,
+        ReadStorage<'a, NetworkRecv>,
+        ReadStorage<'a, Predicted>,
+        ReadStorage<'a, RemoteControlled>,
+        ReadStorage<'a, PlayerName>,
+        ReadStorage<'a, PlayerController>,
+        WriteStorage<'a, Emoting>,
+        Write<'a, Chat>,
+        Write<'a, Gestures>,
+        Read<'a, LocalPlayer>,
+        Read<'a, GameState>,
+        ReadExpect<'a, NetworkMode>,
+        Write<'a, NetworkInbox>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            network_send,
+            network_recv,
+            predicted,
+            remote_controlled,
+            player_name,
+            player_controller,
+            mut emoting,
+            mut chat,
+            mut gestures,
+            local_player,
+            game_state,
+            network_mode,
+            mut inbox,
+        ) = data;
+
+        let is_host = matches!(*network_mode, NetworkMode::Host);
+
+        for text in std::mem::take(&mut chat.outgoing) {
+            let name = local_player.name.clone();
+
+            // The host's copy is the one everyone sees, so clients wait for it to come back
+            if !matches!(*network_mode, NetworkMode::Client) {
+                chat.push(name.clone(), text.clone(), game_state.elapsed);
+            }
+
+            inbox.send(Message::new(
+                "chat/message".to_string(),
+                ChatMessage { name, text },
+            ));
+        }
+
+        gestures
+            .pings
+            .retain(|ping| game_state.elapsed - ping.placed_at < PING_DURATION);
+
+        let finished = (&entities, &emoting)
+            .join()
+            .filter(|(_, emoting)| game_state.elapsed - emoting.started_at > EMOTE_DURATION)
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        for entity in finished {
+            emoting.remove(entity);
+        }
+
+        // Pings and emotes go through the host like chat does
+        for position in std::mem::take(&mut gestures.requested_pings) {
+            let name = local_player.name.clone();
+
+            if !matches!(*network_mode, NetworkMode::Client) {
+                gestures.pings.push(Ping {
+                    position,
+                    name: name.clone(),
+                    placed_at: game_state.elapsed,
+                });
+            }
+
+            inbox.send(Message::new(
+                "gesture/ping".to_string(),
+                PingMessage {
+                    name,
+                    position: position.into(),
+                },
+            ));
+        }
+
+        let local_player_id = (&predicted)
+            .join()
+            .map(|c| c.network_id())
+            .chain(
+                (&network_send, &player_controller)
+                    .join()
+                    .map(|c| c.0.network_id()),
+            )
+            .next();
+
+        for emote in std::mem::take(&mut gestures.requested_emotes) {
+            let network_id = if let Some(network_id) = local_player_id {
+                network_id
+            } else {
+                continue;
+            };
+
+            if !matches!(*network_mode, NetworkMode::Client) {
+                for (entity, _) in (&entities, &player_controller).join() {
+                    emoting
+                        .insert(
+                            entity,
+                            Emoting {
+                                emote,
+                                started_at: game_state.elapsed,
+                            },
+                        )
+                        .unwrap_or_else(|e| {
+                            println!("{}", e);
+                            None
+                        });
+                }
+            }
+
+            inbox.send(Message::new(
+                "gesture/emote".to_string(),
+                EmoteMessage { network_id, emote },
+            ));
+        }
+
+        for msg in inbox.received(&["chat/message", "gesture/ping", "gesture/emote"]) {
+            let sender = msg.sender;
+
+            match msg.msg_type.as_str() {
+                "chat/message" => {
+                    let mut msg: ChatMessage = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    if is_host {
+                        let network_id = match sender {
+                            Some(sender) => sender as u64,
+                            None => continue,
+                        };
+
+                        msg.name = (&remote_controlled, &player_name)
+                            .join()
+                            .find(|c| c.0.network_id() == network_id)
+                            .map(|c| c.1 .0.clone())
+                            .unwrap_or_else(|| format!("Player {}", network_id));
+                        msg.text = msg.text.chars().take(MAX_CHAT_LENGTH).collect();
+
+                        chat.push(msg.name.clone(), msg.text.clone(), game_state.elapsed);
+                        inbox.send(Message::new("chat/message".to_string(), msg));
+                    } else {
+                        chat.push(msg.name, msg.text, game_state.elapsed);
+                    }
+                }
+                "gesture/ping" => {
+                    let mut msg: PingMessage = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    if is_host {
+                        let network_id = match sender {
+                            Some(sender) => sender as u64,
+                            None => continue,
+                        };
+
+                        msg.name = (&remote_controlled, &player_name)
+                            .join()
+                            .find(|c| c.0.network_id() == network_id)
+                            .map(|c| c.1 .0.clone())
+                            .unwrap_or_else(|| format!("Player {}", network_id));
+                    }
+
+                    gestures.pings.push(Ping {
+                        position: msg.position.into(),
+                        name: msg.name.clone(),
+                        placed_at: game_state.elapsed,
+                    });
+
+                    if is_host {
+                        inbox.send(Message::new("gesture/ping".to_string(), msg));
+                    }
+                }
+                "gesture/emote" => {
+                    let msg: EmoteMessage = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    let entity = if is_host {
+                        let player = (&entities, &remote_controlled)
+                            .join()
+                            .find(|c| c.1.network_id() == msg.network_id);
+
+                        match player {
+                            Some((entity, controlled)) if controlled.is_owner(sender) => {
+                                Some(entity)
+                            }
+                            _ => {
+                                println!(
+                                    "Rejected emote for entity {} from client {:?}: not the owner",
+                                    msg.network_id, sender
+                                );
+                                continue;
+                            }
+                        }
+                    } else {
+                        (&entities, &network_recv)
+                            .join()
+                            .find(|c| c.1.network_id() == msg.network_id)
+                            .map(|c| c.0)
+                            .or_else(|| {
+                                (&entities, &predicted)
+                                    .join()
+                                    .find(|c| c.1.network_id() == msg.network_id)
+                                    .map(|c| c.0)
+                            })
+                    };
+
+                    if let Some(entity) = entity {
+                        emoting
+                            .insert(
+                                entity,
+                                Emoting {
+                                    emote: msg.emote,
+                                    started_at: game_state.elapsed,
+                                },
+                            )
+                            .unwrap_or_else(|e| {
+                                println!("{}", e);
+                                None
+                            });
+                    }
+
+                    if is_host {
+                        inbox.send(Message::new("gesture/emote".to_string(), msg));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,42 +1,41 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write as _;
 
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
-use specs::{Component, Join, ReadStorage, System, VecStorage, WriteStorage};
-use tokio::sync::{broadcast, mpsc, Mutex};
+use specs::{
+    Component, Entity, Join, Read, ReadExpect, ReadStorage, System, VecStorage, Write, WriteStorage,
+};
 
 use crate::{
-    components::{Acceleration, Position, Velocity},
+    components::{Acceleration, Grounded, PlayerInput, Position, Velocity},
+    resources::{GameState, NetworkStats},
     util::Vec2,
+    NetworkMode,
 };
 
-use super::systems::{Message, RoomConnectionType, TransmissionNetworkPortal};
-
-pub struct Incrementor {
-    value: usize,
-}
-impl Incrementor {
-    pub fn new() -> Self {
-        Self { value: 1 }
-    }
-}
-impl Iterator for Incrementor {
-    type Item = usize;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let current = self.value;
-        self.value += 1;
-        Some(current)
-    }
-}
+use super::authority::Authority;
+use super::interpolation::{Snapshot, SnapshotBuffer};
+use super::prediction::{EntityInput, EntityState, Predicted, RemoteControlled};
+use super::replication::{
+    self, DeltaDecoder, DeltaEncoder, EntityAck, EntityBatch, ReplicationBuffer,
+};
+use super::spawning::DespawnEntity;
+use super::systems::{ConnectionError, ConnectionStats, ConnectionStatus, Message, PeerEvent};
+use super::transport::Transport;
 
 #[derive(Debug, PartialEq)]
 pub struct NetworkSend {
-    network_id: usize,
+    network_id: u64,
 }
 impl NetworkSend {
-    pub fn new(network_id: usize) -> Self {
+    pub fn new(network_id: u64) -> Self {
         Self { network_id }
     }
+
+    pub fn network_id(&self) -> u64 {
+        self.network_id
+    }
 }
 impl Component for NetworkSend {
     type Storage = VecStorage<Self>;
@@ -44,18 +43,22 @@ impl Component for NetworkSend {
 
 #[derive(Debug, PartialEq)]
 pub struct NetworkRecv {
-    network_id: usize,
+    network_id: u64,
 }
 impl NetworkRecv {
-    pub fn new(network_id: usize) -> Self {
+    pub fn new(network_id: u64) -> Self {
         Self { network_id }
     }
+
+    pub fn network_id(&self) -> u64 {
+        self.network_id
+    }
 }
 impl Component for NetworkRecv {
     type Storage = VecStorage<Self>;
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct Vec2ForSerde {
     pub x: f32,
     pub y: f32,
@@ -73,15 +76,23 @@ impl Into<Vec2> for Vec2ForSerde {
 
 #[derive(Serialize, Deserialize)]
 pub struct UpdateEntity {
-    entity_id: usize,
-    position: Vec2ForSerde,
-    velocity: Vec2ForSerde,
-    acceleration: Vec2ForSerde,
+    pub entity_id: u64,
+    pub timestamp: f64,
+    pub position: Vec2ForSerde,
+    pub velocity: Vec2ForSerde,
+    pub acceleration: Vec2ForSerde,
 }
 impl UpdateEntity {
-    pub fn new(entity_id: usize, position: Vec2, velocity: Vec2, acceleration: Vec2) -> Self {
+    pub fn new(
+        entity_id: u64,
+        timestamp: f64,
+        position: Vec2,
+        velocity: Vec2,
+        acceleration: Vec2,
+    ) -> Self {
         Self {
             entity_id,
+            timestamp,
             position: position.into(),
             velocity: velocity.into(),
             acceleration: acceleration.into(),
@@ -89,64 +100,482 @@ impl UpdateEntity {
     }
 }
 
+/// The host's entities are sent, and everyone else's received, but either way the id is the same.
+pub(super) fn network_id_of(
+    entity: Entity,
+    network_send: &ReadStorage<NetworkSend>,
+    network_recv: &ReadStorage<NetworkRecv>,
+) -> Option<u64> {
+    network_send
+        .get(entity)
+        .map(|c| c.network_id)
+        .or_else(|| network_recv.get(entity).map(|c| c.network_id))
+}
+
+/// Parses the body of a message, discarding it if a peer sent something malformed.
+pub(super) fn decode<T: DeserializeOwned>(msg: Message) -> Option<T> {
+    let msg_type = msg.msg_type;
+    let sender = msg.sender;
+
+    serde_json::from_value(msg.data)
+        .map_err(|e| {
+            println!(
+                "Discarded malformed {} from client {:?}: {}",
+                msg_type, sender, e
+            )
+        })
+        .ok()
+}
+
+/// Where the systems that deal with each part of the protocol, like the `AuthorityHandler`, pick
+/// up what the `NetworkHandler` received for them this frame, and leave what they want sent. The
+/// `NetworkHandler` owns the connection, so it sends those at the start of the next frame.
+#[derive(Default)]
+pub struct NetworkInbox {
+    received: Vec<Message>,
+    outgoing: Vec<Message>,
+}
+impl NetworkInbox {
+    /// Every message of the given types received this frame, in the order they arrived. Others
+    /// may be interested in the same ones, like everyone is in a peer leaving, so they are left in
+    /// place until the next frame.
+    pub fn received(&self, msg_types: &[&str]) -> Vec<Message> {
+        self.received
+            .iter()
+            .filter(|msg| msg_types.contains(&msg.msg_type.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    pub fn send(&mut self, msg: Message) {
+        self.outgoing.push(msg);
+    }
+
+    /// Hands on a message unpacked from another, for the systems that run after this one.
+    pub(super) fn receive(&mut self, msg: Message) {
+        self.received.push(msg);
+    }
+}
+
 pub struct NetworkHandler {
-    portal: Arc<Mutex<TransmissionNetworkPortal>>,
-    channels: (broadcast::Sender<Message>, mpsc::Receiver<Message>),
+    transport: Box<dyn Transport>,
+    send_interval: f64,
+    next_send: f64,
+    encoder: DeltaEncoder,
+    decoders: HashMap<u32, DeltaDecoder>,
+    client_id: u32,
 }
 impl NetworkHandler {
-    pub fn new(
-        portal: Arc<Mutex<TransmissionNetworkPortal>>,
-        channels: (broadcast::Sender<Message>, mpsc::Receiver<Message>),
-    ) -> Self {
-        Self { portal, channels }
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Box::new(transport),
+            send_interval: 1.0 / Self::DEFAULT_SEND_RATE,
+            next_send: 0.0,
+            encoder: DeltaEncoder::default(),
+            decoders: HashMap::new(),
+            client_id: 0,
+        }
+    }
+
+    /// The id this peer was given in the room, needed to tell which acknowledgements are its own.
+    pub fn client_id(mut self, client_id: u32) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    /// How many times a second entity states are sent, regardless of the frame rate.
+    pub fn send_rate(mut self, rate: f64) -> Self {
+        self.send_interval = 1.0 / rate.max(1.0);
+        self
+    }
+}
+impl NetworkHandler {
+    pub const DEFAULT_SEND_RATE: f64 = 30.0;
+
+    fn broadcast(&self, msg: Message) {
+        self.transport.send(msg);
     }
 }
 impl<'a> System<'a> for NetworkHandler {
     type SystemData = (
         ReadStorage<'a, NetworkSend>,
         ReadStorage<'a, NetworkRecv>,
-        WriteStorage<'a, Position>,
-        WriteStorage<'a, Velocity>,
-        WriteStorage<'a, Acceleration>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Acceleration>,
+        ReadStorage<'a, Grounded>,
+        ReadStorage<'a, Authority>,
+        WriteStorage<'a, PlayerInput>,
+        WriteStorage<'a, SnapshotBuffer>,
+        WriteStorage<'a, Predicted>,
+        WriteStorage<'a, RemoteControlled>,
+        Read<'a, GameState>,
+        ReadExpect<'a, NetworkMode>,
+        Write<'a, ConnectionStatus>,
+        Write<'a, NetworkStats>,
+        Write<'a, ReplicationBuffer>,
+        Write<'a, NetworkInbox>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
-        let (network_send, network_recv, mut position, mut velocity, mut acceleration) = data;
+        let (
+            network_send,
+            network_recv,
+            position,
+            velocity,
+            acceleration,
+            grounded,
+            authority,
+            mut player_input,
+            mut snapshot_buffer,
+            mut predicted,
+            mut remote_controlled,
+            game_state,
+            network_mode,
+            mut connection_status,
+            mut network_stats,
+            mut replication,
+            mut inbox,
+        ) = data;
 
-        for (position, velocity, acceleration, network_send) in
-            (&position, &velocity, &acceleration, &network_send).join()
-        {
-            let msg = Message::new(
-                "entity/update".to_string(),
-                UpdateEntity::new(
-                    network_send.network_id,
+        let is_host = matches!(*network_mode, NetworkMode::Host);
+
+        // Anything received last frame has been applied by now, and anything sent is taken
+        // fresh from the replicators each frame
+        replication.clear_incoming();
+        let mut outgoing = std::mem::take(&mut replication.outgoing);
+        inbox.received.clear();
+
+        // What the other handlers had to say goes first, so a spawn goes out before the entity's
+        // first state
+        for msg in std::mem::take(&mut inbox.outgoing) {
+            self.broadcast(msg);
+        }
+
+        let sending = game_state.elapsed >= self.next_send;
+        if sending {
+            self.next_send = (self.next_send + self.send_interval).max(game_state.elapsed);
+
+            let peers = match &*connection_status {
+                ConnectionStatus::Connected(peers) => peers.clone(),
+                _ => vec![],
+            };
+            for (position, velocity, acceleration, network_send) in
+                (&position, &velocity, &acceleration, &network_send).join()
+            {
+                replication::insert_motion(
+                    outgoing.entry(network_send.network_id).or_default(),
+                    position.0,
+                    velocity.0,
+                    acceleration.0,
+                );
+            }
+            // The host sends everything it has, passing on what clients own, but clients only
+            // send what they own
+            for (position, velocity, acceleration, network_recv, _) in (
+                &position,
+                &velocity,
+                &acceleration,
+                &network_recv,
+                &authority,
+            )
+                .join()
+                .filter(|c| c.4.is_local())
+            {
+                replication::insert_motion(
+                    outgoing.entry(network_recv.network_id).or_default(),
                     position.0,
                     velocity.0,
                     acceleration.0,
-                ),
+                );
+            }
+
+            // Anything no longer sent, like a despawned box, needs no baseline kept for it
+            let sent = outgoing.keys().copied().collect::<HashSet<_>>();
+            self.encoder
+                .retain_entities(|entity_id| sent.contains(&entity_id));
+
+            for batch in self.encoder.encode(
+                outgoing,
+                &peers,
+                replication.priorities(),
+                game_state.elapsed,
+            ) {
+                self.broadcast(Message::new("entity/batch".to_string(), batch));
+            }
+        }
+
+        for (position, velocity, input, predicted) in
+            (&position, &velocity, &player_input, &mut predicted).join()
+        {
+            predicted.record(game_state.tick, *input, position.0, velocity.0);
+
+            let msg = Message::new(
+                "entity/input".to_string(),
+                EntityInput {
+                    entity_id: predicted.network_id(),
+                    inputs: predicted.recent_inputs(),
+                },
             );
 
-            self.channels.0.send(msg);
+            self.broadcast(msg);
+        }
+
+        let states = (
+            &position,
+            &velocity,
+            &acceleration,
+            &grounded,
+            &remote_controlled,
+        )
+            .join()
+            .filter(|_| sending)
+            .filter_map(
+                |(position, velocity, acceleration, grounded, remote_controlled)| {
+                    Some(EntityState {
+                        entity_id: remote_controlled.network_id(),
+                        tick: remote_controlled.applied()?,
+                        timestamp: game_state.elapsed,
+                        position: position.0.into(),
+                        velocity: velocity.0.into(),
+                        acceleration: acceleration.0.into(),
+                        grounded: grounded.0,
+                    })
+                },
+            )
+            .collect::<Vec<_>>();
+
+        if !states.is_empty() {
+            self.broadcast(Message::new("entity/states".to_string(), states));
         }
 
-        while let Ok(msg) = self.channels.1.try_recv() {
-            if msg.msg_type != "entity/update" {
-                continue;
+        let mut unpacked = VecDeque::new();
+        let mut received_batches: HashMap<u32, Vec<u64>> = HashMap::new();
+
+        loop {
+            let msg = match unpacked.pop_front() {
+                Some(msg) => msg,
+                None => match self.transport.try_recv() {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
+            let sender = msg.sender;
+
+            match msg.msg_type.as_str() {
+                "entity/batch" => {
+                    let msg: EntityBatch = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    let peer = match sender {
+                        Some(sender) => sender,
+                        None => continue,
+                    };
+
+                    let states = self.decoders.entry(peer).or_default().decode(&msg);
+                    received_batches.entry(peer).or_default().push(msg.seq);
+
+                    for (entity_id, mut state) in states {
+                        // Motion goes through the same checks as a full update
+                        if let Some((position, velocity, acceleration)) =
+                            replication::take_motion(&mut state)
+                        {
+                            let update = UpdateEntity::new(
+                                entity_id,
+                                msg.timestamp,
+                                position,
+                                velocity,
+                                acceleration,
+                            );
+                            let mut msg = Message::new("entity/update".to_string(), update);
+                            msg.sender = sender;
+                            unpacked.push_back(msg);
+                        }
+
+                        replication.receive(entity_id, sender, state);
+                    }
+                }
+                "entity/ack" => {
+                    let msg: EntityAck = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    // Clients hear the host acknowledge everyone's batches, so only take their own
+                    let for_us = msg.client.is_none_or(|client| client == self.client_id);
+                    if let (Some(sender), true) = (sender, for_us) {
+                        self.encoder.acknowledge(sender, &msg.seqs);
+                    }
+                }
+                "entity/states" => {
+                    let msg: Vec<EntityState> = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    for state in msg {
+                        let mut msg = Message::new("entity/state".to_string(), state);
+                        msg.sender = sender;
+                        unpacked.push_back(msg);
+                    }
+                }
+                "entity/input" => {
+                    let msg: EntityInput = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    for remote_controlled in (&mut remote_controlled)
+                        .join()
+                        .filter(|c| c.network_id() == msg.entity_id)
+                    {
+                        if !remote_controlled.is_owner(sender) {
+                            println!(
+                                "Rejected input for entity {} from client {:?}: not the owner",
+                                msg.entity_id, sender
+                            );
+                            continue;
+                        }
+
+                        remote_controlled.enqueue(msg.inputs.clone());
+                    }
+                }
+                "entity/state" => {
+                    let msg: EntityState = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    if is_host {
+                        println!(
+                            "Rejected state for entity {} from client {:?}: only the host is authoritative",
+                            msg.entity_id, sender
+                        );
+                        continue;
+                    }
+
+                    for predicted in (&mut predicted)
+                        .join()
+                        .filter(|c| c.network_id() == msg.entity_id)
+                    {
+                        predicted.receive(msg);
+                    }
+
+                    for (snapshot_buffer, _) in (&mut snapshot_buffer, &network_recv)
+                        .join()
+                        .filter(|c| c.1.network_id == msg.entity_id)
+                    {
+                        snapshot_buffer.push(
+                            Snapshot::new(
+                                msg.timestamp,
+                                msg.position.into(),
+                                msg.velocity.into(),
+                                msg.acceleration.into(),
+                            ),
+                            game_state.elapsed,
+                        );
+                    }
+                }
+                "peer/lost" if sender.is_none() && is_host => {
+                    let msg: PeerEvent = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    for (input, _) in (&mut player_input, &remote_controlled)
+                        .join()
+                        .filter(|c| c.1.network_id() == msg.client_id as u64)
+                    {
+                        *input = PlayerInput::default();
+                    }
+                }
+                "connection/status" if sender.is_none() => {
+                    let msg: ConnectionStatus = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    println!("Connection status: {:?}", msg);
+                    *connection_status = msg;
+                }
+                "connection/stats" if sender.is_none() => {
+                    let mut msg: ConnectionStats = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    // Only clients interpolate, and everything they interpolate comes from the
+                    // host, so the buffer closest to running dry is the one worth showing
+                    if !is_host {
+                        let depth = (&network_recv, &snapshot_buffer)
+                            .join()
+                            .map(|c| c.1.depth())
+                            .min();
+                        for peer in msg.peers.iter_mut() {
+                            peer.snapshot_depth = depth;
+                        }
+                    }
+
+                    if let Some(log) = network_stats.log.as_mut() {
+                        for peer in &msg.peers {
+                            if let Err(e) = writeln!(
+                                log,
+                                "{:.3} {}",
+                                game_state.elapsed,
+                                serde_json::to_string(peer).unwrap_or_default()
+                            ) {
+                                println!("Could not write network statistics: {}", e);
+                                network_stats.log = None;
+                                break;
+                            }
+                        }
+                    }
+
+                    network_stats.peers = msg.peers;
+                }
+                "connection/error" if sender.is_none() => {
+                    let msg: ConnectionError = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    println!("Network error: {}", msg.msg);
+                }
+                "entity/despawn" if !is_host => {
+                    inbox.receive(msg.clone());
+                    let msg: DespawnEntity = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    for decoder in self.decoders.values_mut() {
+                        decoder.forget_entity(msg.network_id);
+                    }
+                }
+                "peer/leave" if sender.is_none() => {
+                    inbox.receive(msg.clone());
+                    let msg: PeerEvent = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    self.encoder.forget_peer(msg.client_id);
+                    self.decoders.remove(&msg.client_id);
+                }
+                // The rest is left to the handlers for each part of the protocol
+                _ => inbox.receive(msg),
             }
+        }
 
-            let msg: UpdateEntity = serde_json::from_value(msg.data).unwrap();
-            for (mut position, mut velocity, mut acceleration, network_recv) in (
-                &mut position,
-                &mut velocity,
-                &mut acceleration,
-                &network_recv,
-            )
-                .join()
-                .filter(|c| c.3.network_id == msg.entity_id)
-            {
-                position.0 = msg.position.into();
-                velocity.0 = msg.velocity.into();
-                acceleration.0 = msg.acceleration.into();
+        for (peer, seqs) in received_batches {
+            self.broadcast(Message::new(
+                "entity/ack".to_string(),
+                EntityAck {
+                    seqs,
+                    client: is_host.then_some(peer),
+                },
+            ));
+        }
+
+        for (input, remote_controlled) in (&mut player_input, &mut remote_controlled).join() {
+            if let Some(next) = remote_controlled.next_input() {
+                *input = next;
             }
         }
     }
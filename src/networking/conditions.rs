@@ -0,0 +1,86 @@
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+
+/// How much longer a reordered datagram is held back, on top of the usual delay, so that the ones
+/// sent after it overtake it.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Bad network conditions to put on outgoing datagrams, for testing how the game copes with them
+/// without needing a real remote peer. Probabilities are between 0 and 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkConditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+}
+impl NetworkConditions {
+    pub fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// How long to hold each copy of a datagram back for. Dropped datagrams have no copies.
+    fn delays(&self) -> Vec<Duration> {
+        let mut rng = rand::thread_rng();
+
+        if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+            return vec![];
+        }
+
+        let copies = if rng.gen_bool(self.duplicate.clamp(0.0, 1.0)) {
+            2
+        } else {
+            1
+        };
+
+        (0..copies)
+            .map(|_| {
+                let mut delay = self.latency + self.jitter.mul_f64(rng.gen::<f64>());
+
+                if rng.gen_bool(self.reorder.clamp(0.0, 1.0)) {
+                    delay += self.jitter + REORDER_DELAY;
+                }
+
+                delay
+            })
+            .collect()
+    }
+}
+
+/// A way of getting sealed packets to a peer, such as straight over UDP or through the relay.
+pub trait Link: Clone + Send + Sync + 'static {
+    fn send(&self, packet: Vec<u8>) -> impl Future<Output = ()> + Send;
+}
+
+/// Wraps a link to simulate a bad network on everything sent over it. Conditions only apply to
+/// what this end sends, so both ends need them to affect traffic in both directions.
+#[derive(Debug, Clone)]
+pub struct Conditioned<L> {
+    link: L,
+    conditions: NetworkConditions,
+}
+impl<L: Link> Conditioned<L> {
+    pub fn new(link: L, conditions: NetworkConditions) -> Self {
+        Self { link, conditions }
+    }
+}
+impl<L: Link> Link for Conditioned<L> {
+    async fn send(&self, packet: Vec<u8>) {
+        if self.conditions.is_perfect() {
+            self.link.send(packet).await;
+            return;
+        }
+
+        for delay in self.conditions.delays() {
+            let link = self.link.clone();
+            let packet = packet.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                link.send(packet).await;
+            });
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const COUNTER_SIZE: usize = 8;
+const REPLAY_WINDOW: u64 = 64;
+
+/// The key pair a client uses for every peer it meets. Public keys are passed around by the
+/// rendezvous server along with the rest of a peer's connection details.
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+impl Identity {
+    pub fn new() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> String {
+        base64::encode(self.public.as_bytes())
+    }
+
+    /// Agrees on a key with a peer. The room id and both client ids are mixed in, so the key is
+    /// only good for traffic between these two clients in this room.
+    pub fn session(
+        &self,
+        peer_public_key: &str,
+        room_id: &str,
+        local_id: u32,
+        remote_id: u32,
+    ) -> Option<(Sealer, Opener)> {
+        let peer_public: [u8; 32] = base64::decode(peer_public_key).ok()?.try_into().ok()?;
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer_public));
+
+        let mut info = b"team-platformer session".to_vec();
+        info.extend_from_slice(&local_id.min(remote_id).to_be_bytes());
+        info.extend_from_slice(&local_id.max(remote_id).to_be_bytes());
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(room_id.as_bytes()), shared.as_bytes())
+            .expand(&info, &mut key)
+            .ok()?;
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+        Some((
+            Sealer {
+                cipher: cipher.clone(),
+                local_id,
+                counter: 0,
+            },
+            Opener {
+                cipher,
+                remote_id,
+                highest: None,
+                seen: 0,
+            },
+        ))
+    }
+}
+
+/// Both directions share a key, so the sender's id goes into the nonce to keep them apart.
+fn nonce(sender_id: u32, counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&sender_id.to_be_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+    *Nonce::from_slice(&nonce)
+}
+
+/// Encrypts outgoing packets for one peer.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    local_id: u32,
+    counter: u64,
+}
+impl Sealer {
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.counter;
+        self.counter += 1;
+
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce(self.local_id, counter), plaintext)
+            .expect("encrypting into a Vec cannot fail");
+
+        let mut packet = counter.to_be_bytes().to_vec();
+        packet.extend(ciphertext);
+        packet
+    }
+}
+impl Debug for Sealer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sealer")
+            .field("counter", &self.counter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Decrypts incoming packets from one peer, rejecting any that were tampered with or replayed.
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    remote_id: u32,
+    highest: Option<u64>,
+    seen: u64,
+}
+impl Opener {
+    pub fn open(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < COUNTER_SIZE {
+            return None;
+        }
+
+        let (counter, ciphertext) = packet.split_at(COUNTER_SIZE);
+        let counter = u64::from_be_bytes(counter.try_into().ok()?);
+
+        if self.replayed(counter) {
+            return None;
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce(self.remote_id, counter), ciphertext)
+            .ok()?;
+
+        self.accept(counter);
+
+        Some(plaintext)
+    }
+
+    /// The highest packet counter accepted so far.
+    pub fn highest(&self) -> Option<u64> {
+        self.highest
+    }
+
+    fn replayed(&self, counter: u64) -> bool {
+        match self.highest {
+            Some(highest) if counter > highest => false,
+            Some(highest) => {
+                let age = highest - counter;
+                age >= REPLAY_WINDOW || self.seen & (1 << age) != 0
+            }
+            None => false,
+        }
+    }
+
+    /// `seen` has a bit set for each of the last packets accepted, counting back from the highest.
+    fn accept(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter > highest => {
+                let shift = counter - highest;
+                self.seen = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.seen << shift
+                };
+                self.seen |= 1;
+                self.highest = Some(counter);
+            }
+            Some(highest) => self.seen |= 1 << (highest - counter),
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+impl Debug for Opener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Opener")
+            .field("highest", &self.highest)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+
+use specs::{Component, Join, Read, System, VecStorage, WriteStorage};
+
+use crate::components::{Acceleration, Position, Velocity};
+use crate::resources::GameState;
+use crate::util::Vec2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub timestamp: f64,
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub acceleration: Vec2,
+}
+impl Snapshot {
+    pub fn new(timestamp: f64, position: Vec2, velocity: Vec2, acceleration: Vec2) -> Self {
+        Self {
+            timestamp,
+            position,
+            velocity,
+            acceleration,
+        }
+    }
+
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            timestamp: self.timestamp + (other.timestamp - self.timestamp) * t as f64,
+            position: self.position.lerp(&other.position, t),
+            velocity: self.velocity.lerp(&other.velocity, t),
+            acceleration: self.acceleration.lerp(&other.acceleration, t),
+        }
+    }
+}
+
+pub enum Sample {
+    Interpolated(Snapshot),
+    Extrapolate(Snapshot),
+    Extrapolating,
+}
+
+#[derive(Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+    clock_offset: Option<f64>,
+    extrapolating: bool,
+}
+impl SnapshotBuffer {
+    const CAPACITY: usize = 64;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers a snapshot timestamped by the sender's clock. The offset between the sender's clock
+    /// and the local clock is estimated from the fastest snapshot seen so far, so jitter only ever
+    /// makes snapshots arrive later than expected rather than shifting the timeline.
+    pub fn push(&mut self, snapshot: Snapshot, received_at: f64) {
+        let offset = received_at - snapshot.timestamp;
+        self.clock_offset = Some(self.clock_offset.map_or(offset, |o| o.min(offset)));
+
+        if let Some(last) = self.snapshots.back() {
+            if snapshot.timestamp <= last.timestamp {
+                return;
+            }
+        }
+
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > Self::CAPACITY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// How many snapshots are waiting to be rendered.
+    pub fn depth(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn sample(&mut self, local_time: f64, delay: f64) -> Option<Sample> {
+        let render_time = local_time - self.clock_offset? - delay;
+
+        while self.snapshots.len() >= 2 && self.snapshots[1].timestamp <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let from = *self.snapshots.front()?;
+
+        if render_time <= from.timestamp {
+            self.extrapolating = false;
+            return Some(Sample::Interpolated(from));
+        }
+
+        match self.snapshots.get(1) {
+            Some(to) => {
+                self.extrapolating = false;
+
+                let t = (render_time - from.timestamp) / (to.timestamp - from.timestamp);
+                Some(Sample::Interpolated(from.lerp(to, t as f32)))
+            }
+            None if self.extrapolating => Some(Sample::Extrapolating),
+            None => {
+                self.extrapolating = true;
+                Some(Sample::Extrapolate(from))
+            }
+        }
+    }
+}
+impl Component for SnapshotBuffer {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterpolationSettings {
+    pub delay: f64,
+}
+impl InterpolationSettings {
+    pub fn new(delay: f64) -> Self {
+        Self { delay }
+    }
+}
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self { delay: 0.1 }
+    }
+}
+
+/// Renders remote entities `delay` seconds in the past, between the two buffered snapshots either
+/// side of that time. Once the buffer runs dry the entity is left to the local physics systems
+/// until fresh snapshots arrive.
+pub struct SnapshotInterpolationSystem;
+impl<'a> System<'a> for SnapshotInterpolationSystem {
+    type SystemData = (
+        WriteStorage<'a, SnapshotBuffer>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        WriteStorage<'a, Acceleration>,
+        Read<'a, InterpolationSettings>,
+        Read<'a, GameState>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (mut buffers, mut position, mut velocity, mut acceleration, settings, game_state) =
+            data;
+
+        for (buffer, position, velocity, acceleration) in (
+            &mut buffers,
+            &mut position,
+            &mut velocity,
+            &mut acceleration,
+        )
+            .join()
+        {
+            let snapshot = match buffer.sample(game_state.elapsed, settings.delay) {
+                Some(Sample::Interpolated(snapshot)) | Some(Sample::Extrapolate(snapshot)) => {
+                    snapshot
+                }
+                Some(Sample::Extrapolating) | None => continue,
+            };
+
+            position.0 = snapshot.position;
+            velocity.0 = snapshot.velocity;
+            acceleration.0 = snapshot.acceleration;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A snapshot of an entity at `x`, which always arrives a second after it was sent.
+    fn push(buffer: &mut SnapshotBuffer, timestamp: f64, x: f32) {
+        let snapshot = Snapshot::new(
+            timestamp,
+            Vec2::new(x, 0.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(0.0, 0.0),
+        );
+        buffer.push(snapshot, timestamp + 1.0);
+    }
+
+    fn interpolated(sample: Option<Sample>) -> f32 {
+        match sample {
+            Some(Sample::Interpolated(snapshot)) => snapshot.position.x,
+            _ => panic!("the sample was not interpolated"),
+        }
+    }
+
+    #[test]
+    fn a_sample_between_snapshots_is_interpolated() {
+        let mut buffer = SnapshotBuffer::new();
+        push(&mut buffer, 0.0, 0.0);
+        push(&mut buffer, 0.1, 10.0);
+
+        let x = interpolated(buffer.sample(1.15, 0.1));
+        assert!((x - 5.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn extrapolation_past_the_newest_snapshot_only_starts_once() {
+        let mut buffer = SnapshotBuffer::new();
+        push(&mut buffer, 0.0, 0.0);
+        push(&mut buffer, 0.1, 10.0);
+
+        // The newest snapshot is handed over once, and the local physics carry on from there
+        match buffer.sample(1.3, 0.1) {
+            Some(Sample::Extrapolate(snapshot)) => assert_eq!(snapshot.position.x, 10.0),
+            _ => panic!("extrapolation did not start from the newest snapshot"),
+        }
+        assert!(matches!(
+            buffer.sample(1.35, 0.1),
+            Some(Sample::Extrapolating)
+        ));
+
+        push(&mut buffer, 0.3, 30.0);
+        let x = interpolated(buffer.sample(1.35, 0.1));
+        assert!((x - 25.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn late_and_repeated_snapshots_are_ignored() {
+        let mut buffer = SnapshotBuffer::new();
+        push(&mut buffer, 0.0, 0.0);
+        push(&mut buffer, 0.2, 20.0);
+        push(&mut buffer, 0.1, 99.0);
+        push(&mut buffer, 0.2, 99.0);
+        assert_eq!(buffer.depth(), 2);
+
+        let x = interpolated(buffer.sample(1.2, 0.1));
+        assert!((x - 10.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use sdl2::keyboard::Keycode;
+use serde_derive::{Deserialize, Serialize};
+use specs::{
+    Builder, Component, Entities, Join, LazyUpdate, Read, ReadExpect, ReadStorage, System,
+    VecStorage, Write, WriteStorage,
+};
+
+use crate::components::{PlayerInput, Position, Velocity};
+use crate::game::TICK_RATE;
+use crate::prefabs;
+use crate::resources::{GameState, Lobby, LocalPlayer};
+use crate::util::Vec2;
+use crate::NetworkMode;
+
+use super::components::decode;
+use super::systems::{ConnectionStatus, Message, PeerEvent, PeerJoined};
+use super::transport::Transport;
+
+/// Ticks between sampling an input and simulating it, which is how long everyone's input has to
+/// reach the host and come back as a frame before the game stalls.
+pub const INPUT_DELAY: u64 = 6;
+/// How many ticks of the host's own hashes are kept to check clients' against.
+const HASH_HISTORY: u64 = 600;
+/// How long the host holds the game up waiting on a client's input before it plays on without
+/// them, counted in runs of the `LockstepHandler`, which runs at the tick rate even when stalled.
+const INPUT_TIMEOUT: u32 = 3 * TICK_RATE;
+
+/// Everything one player did on one tick.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct LockstepInput {
+    pub movement: PlayerInput,
+    pub spawn_box: bool,
+}
+impl LockstepInput {
+    fn from_keys(keys: &SampledKeys) -> Self {
+        Self {
+            movement: PlayerInput {
+                left: keys.held.contains(&Keycode::A),
+                right: keys.held.contains(&Keycode::D),
+                jump: keys.held.contains(&Keycode::W) || keys.held.contains(&Keycode::Space),
+            },
+            spawn_box: keys.spawn_box,
+        }
+    }
+}
+
+/// The keys that go into the next input, as the frame rate and the tick rate no longer line up.
+#[derive(Default)]
+struct SampledKeys {
+    held: Vec<Keycode>,
+    spawn_box: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LockstepPlayer {
+    pub client_id: u32,
+    pub name: String,
+}
+
+/// Sent by the host once everyone is in, fixing who is playing for the rest of the game.
+#[derive(Serialize, Deserialize)]
+struct LockstepStart {
+    players: Vec<LockstepPlayer>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InputMessage {
+    tick: u64,
+    input: LockstepInput,
+}
+
+/// Everyone's input for a tick, gathered by the host. Players missing from it stand still.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Frame {
+    tick: u64,
+    inputs: BTreeMap<u32, LockstepInput>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateHash {
+    tick: u64,
+    hash: u64,
+}
+
+/// The first tick on which a client's world stopped matching the host's.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Desync {
+    pub tick: u64,
+    pub client_id: u32,
+}
+
+/// A player driven by the inputs in each frame, rather than by the keyboard.
+#[derive(Debug)]
+pub struct LockstepControlled {
+    pub client_id: u32,
+}
+impl Component for LockstepControlled {
+    type Storage = VecStorage<Self>;
+}
+
+/// Shared between the `LockstepHandler`, which talks to the other peers, and the systems that run
+/// each tick of the simulation.
+#[derive(Default)]
+pub struct Lockstep {
+    /// Everyone in the game, once the host has started it.
+    pub players: Option<Vec<LockstepPlayer>>,
+    /// The next tick to simulate.
+    pub tick: u64,
+    pub desync: Option<Desync>,
+    /// Why the game cannot go on, like the host having left, once no more frames will come.
+    pub ended: Option<String>,
+    frames: BTreeMap<u64, Frame>,
+    keys: SampledKeys,
+    outgoing: Vec<(u64, LockstepInput)>,
+    hashes: Vec<(u64, u64)>,
+}
+impl Lockstep {
+    /// Whether the next tick can be simulated, which it can only once the frame for it is in.
+    pub fn ready(&self) -> bool {
+        self.frames.contains_key(&self.tick)
+    }
+
+    fn start(&mut self, players: Vec<LockstepPlayer>) {
+        // Nobody has sampled any input for the first few ticks, so they are played empty
+        for tick in 0..INPUT_DELAY {
+            self.frames.insert(
+                tick,
+                Frame {
+                    tick,
+                    inputs: BTreeMap::new(),
+                },
+            );
+        }
+        self.players = Some(players);
+    }
+}
+
+/// FNV-1a, as the hash has to come out the same on every peer whatever it was built with.
+fn hash_bits(hash: u64, bits: u32) -> u64 {
+    bits.to_le_bytes().iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Applies the inputs from the frame for this tick to the players they belong to, and samples the
+/// local player's input for a tick that is still to come.
+pub struct LockstepInputSystem;
+impl<'a> System<'a> for LockstepInputSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, LockstepControlled>,
+        ReadStorage<'a, Position>,
+        WriteStorage<'a, PlayerInput>,
+        Write<'a, Lockstep>,
+        Read<'a, LazyUpdate>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, controlled, position, mut player_input, mut lockstep, lazy) = data;
+
+        let tick = lockstep.tick;
+        let frame = match lockstep.frames.remove(&tick) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        let input = LockstepInput::from_keys(&lockstep.keys);
+        lockstep.keys.spawn_box = false;
+        lockstep.outgoing.push((tick + INPUT_DELAY, input));
+
+        for (controlled, pos, player_input) in (&controlled, &position, &mut player_input).join() {
+            let input = frame
+                .inputs
+                .get(&controlled.client_id)
+                .copied()
+                .unwrap_or_default();
+
+            *player_input = input.movement;
+            if input.spawn_box {
+                prefabs::crate_box(pos.0 + Vec2::new(0.0, 2.0), 1.0)
+                    .build(lazy.create_entity(&entities))
+                    .build();
+            }
+        }
+    }
+}
+
+/// Hashes where everything is and how it is moving at the end of each tick, so that the host can
+/// spot a client whose simulation has drifted from its own.
+pub struct DesyncDetector;
+impl<'a> System<'a> for DesyncDetector {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        Write<'a, Lockstep>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, position, velocity, mut lockstep) = data;
+
+        let hash = (&entities, &position, &velocity).join().fold(
+            0xcbf29ce484222325,
+            |hash, (entity, pos, vel)| {
+                [pos.0.x, pos.0.y, vel.0.x, vel.0.y]
+                    .iter()
+                    .fold(hash_bits(hash, entity.id()), |hash, value| {
+                        hash_bits(hash, value.to_bits())
+                    })
+            },
+        );
+
+        // The tick is over once its state has been hashed
+        let tick = lockstep.tick;
+        lockstep.hashes.push((tick, hash));
+        lockstep.tick += 1;
+    }
+}
+
+/// Exchanges inputs and hashes with the other peers. Runs every frame, whether or not there is a
+/// tick ready to simulate, so it is kept out of the simulation's dispatcher.
+pub struct LockstepHandler {
+    transport: Box<dyn Transport>,
+    client_id: u32,
+    names: HashMap<u32, String>,
+    /// The clients the host still waits for before a frame is complete.
+    waiting_for: BTreeSet<u32>,
+    /// How many runs in a row the next frame has been held up by a client.
+    stalled_for: u32,
+    pending: BTreeMap<u64, BTreeMap<u32, LockstepInput>>,
+    hashes: BTreeMap<u64, u64>,
+    /// Hashes from clients that are ahead of the host.
+    early_hashes: BTreeMap<u64, Vec<(u32, u64)>>,
+}
+impl LockstepHandler {
+    pub fn new(transport: impl Transport + 'static, client_id: u32) -> Self {
+        Self {
+            transport: Box::new(transport),
+            client_id,
+            names: HashMap::new(),
+            waiting_for: BTreeSet::new(),
+            stalled_for: 0,
+            pending: BTreeMap::new(),
+            hashes: BTreeMap::new(),
+            early_hashes: BTreeMap::new(),
+        }
+    }
+
+    fn broadcast(&self, msg: Message) {
+        self.transport.send(msg);
+    }
+
+    fn check_hash(&self, lockstep: &mut Lockstep, tick: u64, client_id: u32, hash: u64) {
+        if self.hashes.get(&tick).is_none_or(|&own| own == hash) || lockstep.desync.is_some() {
+            return;
+        }
+
+        let desync = Desync { tick, client_id };
+        println!(
+            "Client {} desynced on tick {}, and has been simulating a different game since",
+            client_id, tick
+        );
+        self.broadcast(Message::new("lockstep/desync".to_string(), desync));
+        lockstep.desync = Some(desync);
+    }
+}
+impl<'a> System<'a> for LockstepHandler {
+    type SystemData = (
+        Write<'a, Lockstep>,
+        Write<'a, ConnectionStatus>,
+        Read<'a, GameState>,
+        Read<'a, LocalPlayer>,
+        ReadExpect<'a, NetworkMode>,
+        Write<'a, Lobby>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            mut lockstep,
+            mut connection_status,
+            game_state,
+            local_player,
+            network_mode,
+            mut lobby,
+        ) = data;
+
+        let is_host = matches!(*network_mode, NetworkMode::Host);
+
+        while let Some(msg) = self.transport.try_recv() {
+            let sender = msg.sender;
+
+            match msg.msg_type.as_str() {
+                "connection/status" if sender.is_none() => {
+                    if let Some(msg) = decode::<ConnectionStatus>(msg) {
+                        println!("Connection status: {:?}", msg);
+                        // Clients only hear from the host, so without it there is nothing to play
+                        if let (ConnectionStatus::Lost(reason), false) = (&msg, is_host) {
+                            lockstep.ended.get_or_insert_with(|| reason.clone());
+                        }
+                        *connection_status = msg;
+                    }
+                }
+                "room/closed" if sender.is_none() && !is_host => {
+                    lockstep
+                        .ended
+                        .get_or_insert_with(|| "The host left the room".to_string());
+                }
+                "peer/join" | "peer/rejoin" if sender.is_none() && is_host => {
+                    if let Some(msg) = decode::<PeerJoined>(msg) {
+                        self.names.insert(msg.client_id, msg.name);
+                    }
+                }
+                "peer/leave" if sender.is_none() && is_host => {
+                    // Whoever leaves stands still from then on, rather than holding everyone up
+                    if let Some(msg) = decode::<PeerEvent>(msg) {
+                        self.waiting_for.remove(&msg.client_id);
+                    }
+                }
+                "lockstep/start" if !is_host && lockstep.players.is_none() => {
+                    if let Some(msg) = decode::<LockstepStart>(msg) {
+                        lobby.started = true;
+                        lockstep.start(msg.players);
+                    }
+                }
+                "lockstep/input" if is_host => {
+                    let client_id = match sender {
+                        Some(client_id) if self.waiting_for.contains(&client_id) => client_id,
+                        _ => continue,
+                    };
+                    if let Some(msg) = decode::<InputMessage>(msg) {
+                        if msg.tick >= lockstep.tick {
+                            self.pending
+                                .entry(msg.tick)
+                                .or_default()
+                                .insert(client_id, msg.input);
+                        }
+                    }
+                }
+                "lockstep/frame" if !is_host => {
+                    if let Some(frame) = decode::<Frame>(msg) {
+                        if frame.tick >= lockstep.tick {
+                            lockstep.frames.insert(frame.tick, frame);
+                        }
+                    }
+                }
+                "lockstep/hash" if is_host => {
+                    let client_id = match sender {
+                        Some(client_id) => client_id,
+                        None => continue,
+                    };
+                    if let Some(msg) = decode::<StateHash>(msg) {
+                        if msg.tick < lockstep.tick {
+                            self.check_hash(&mut lockstep, msg.tick, client_id, msg.hash);
+                        } else {
+                            self.early_hashes
+                                .entry(msg.tick)
+                                .or_default()
+                                .push((client_id, msg.hash));
+                        }
+                    }
+                }
+                "lockstep/desync" if !is_host && lockstep.desync.is_none() => {
+                    if let Some(desync) = decode::<Desync>(msg) {
+                        println!(
+                            "Client {} desynced from the host on tick {}",
+                            desync.client_id, desync.tick
+                        );
+                        lockstep.desync = Some(desync);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Nobody can join once the players are fixed, so the host waits for the room to fill or
+        // to be told to start
+        if lockstep.players.is_none() {
+            let peers = match &*connection_status {
+                ConnectionStatus::Connected(peers) if is_host && lobby.ready(peers) => {
+                    peers.clone()
+                }
+                _ => return,
+            };
+
+            let mut players = vec![LockstepPlayer {
+                client_id: self.client_id,
+                name: local_player.name.clone(),
+            }];
+            for &client_id in &peers {
+                players.push(LockstepPlayer {
+                    client_id,
+                    name: self
+                        .names
+                        .get(&client_id)
+                        .cloned()
+                        .unwrap_or_else(|| format!("Player {}", client_id)),
+                });
+            }
+            players.sort_by_key(|player| player.client_id);
+
+            self.waiting_for = peers.into_iter().collect();
+            self.broadcast(Message::new(
+                "lockstep/start".to_string(),
+                LockstepStart {
+                    players: players.clone(),
+                },
+            ));
+            lobby.started = true;
+            lockstep.start(players);
+        }
+
+        // Frames may not come often enough to catch every key press, so they are held onto until
+        // an input goes out
+        lockstep.keys.held = game_state.keys_held.iter().copied().collect();
+        lockstep.keys.spawn_box |= game_state.keys_pressed.contains(&Keycode::B);
+
+        for (tick, input) in std::mem::take(&mut lockstep.outgoing) {
+            if is_host {
+                self.pending
+                    .entry(tick)
+                    .or_default()
+                    .insert(self.client_id, input);
+            } else {
+                self.broadcast(Message::new(
+                    "lockstep/input".to_string(),
+                    InputMessage { tick, input },
+                ));
+            }
+        }
+
+        // Frames go out in order, so one still missing an input holds back the rest. A client
+        // that stays silent for too long is left to stand still, as if it had left.
+        while let Some(entry) = self.pending.first_entry() {
+            let missing = self
+                .waiting_for
+                .iter()
+                .copied()
+                .filter(|client_id| !entry.get().contains_key(client_id))
+                .collect::<Vec<_>>();
+            if !entry.get().contains_key(&self.client_id) {
+                break;
+            }
+            if !missing.is_empty() && self.stalled_for < INPUT_TIMEOUT {
+                self.stalled_for += 1;
+                break;
+            }
+
+            for client_id in missing {
+                println!(
+                    "Client {} sent no input for {} seconds, so the game goes on without it",
+                    client_id,
+                    INPUT_TIMEOUT / TICK_RATE
+                );
+                self.waiting_for.remove(&client_id);
+            }
+            self.stalled_for = 0;
+
+            let (tick, inputs) = entry.remove_entry();
+            let frame = Frame { tick, inputs };
+            self.broadcast(Message::new("lockstep/frame".to_string(), frame.clone()));
+            lockstep.frames.insert(tick, frame);
+        }
+
+        for (tick, hash) in std::mem::take(&mut lockstep.hashes) {
+            if !is_host {
+                self.broadcast(Message::new(
+                    "lockstep/hash".to_string(),
+                    StateHash { tick, hash },
+                ));
+                continue;
+            }
+
+            self.hashes.insert(tick, hash);
+            for (client_id, hash) in self.early_hashes.remove(&tick).unwrap_or_default() {
+                self.check_hash(&mut lockstep, tick, client_id, hash);
+            }
+        }
+        let oldest = lockstep.tick.saturating_sub(HASH_HISTORY);
+        self.hashes.retain(|&tick, _| tick >= oldest);
+    }
+}
@@ -1,2 +1,13 @@
+pub mod authority;
+pub mod chat;
 pub mod components;
+pub mod conditions;
+pub mod crypto;
+pub mod interpolation;
+pub mod lockstep;
+pub mod prediction;
+pub mod replication;
+pub mod spawning;
 pub mod systems;
+pub mod transport;
+pub mod validation;
@@ -0,0 +1,367 @@
+use std::collections::VecDeque;
+
+use serde_derive::{Deserialize, Serialize};
+use specs::{Component, Entity, Join, RunNow, VecStorage, World, WorldExt};
+
+use crate::components::{Acceleration, Grounded, PlayerInput, Position, Velocity};
+use crate::systems::{EntityMovementSystem, FloorColliderSystem, PlayerMovementSystem};
+use crate::util::Vec2;
+
+use super::components::Vec2ForSerde;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TickInput {
+    pub tick: u64,
+    pub input: PlayerInput,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EntityInput {
+    pub entity_id: u64,
+    pub inputs: Vec<TickInput>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct EntityState {
+    pub entity_id: u64,
+    pub tick: u64,
+    pub timestamp: f64,
+    pub position: Vec2ForSerde,
+    pub velocity: Vec2ForSerde,
+    pub acceleration: Vec2ForSerde,
+    pub grounded: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PredictedTick {
+    tick: u64,
+    input: PlayerInput,
+    position: Vec2,
+    velocity: Vec2,
+}
+
+pub struct Predicted {
+    network_id: u64,
+    history: VecDeque<PredictedTick>,
+    authoritative: Option<EntityState>,
+}
+impl Predicted {
+    const MAX_HISTORY: usize = 256;
+    const REDUNDANT_INPUTS: usize = 8;
+    const TOLERANCE: f32 = 0.01;
+
+    pub fn new(network_id: u64) -> Self {
+        Self {
+            network_id,
+            history: VecDeque::new(),
+            authoritative: None,
+        }
+    }
+
+    pub fn network_id(&self) -> u64 {
+        self.network_id
+    }
+
+    pub fn record(&mut self, tick: u64, input: PlayerInput, position: Vec2, velocity: Vec2) {
+        self.history.push_back(PredictedTick {
+            tick,
+            input,
+            position,
+            velocity,
+        });
+
+        while self.history.len() > Self::MAX_HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    /// The most recent unacknowledged inputs. Several are sent with every packet so that a single
+    /// dropped datagram does not lose an input.
+    pub fn recent_inputs(&self) -> Vec<TickInput> {
+        self.history
+            .iter()
+            .rev()
+            .take(Self::REDUNDANT_INPUTS)
+            .rev()
+            .map(|t| TickInput {
+                tick: t.tick,
+                input: t.input,
+            })
+            .collect()
+    }
+
+    pub fn receive(&mut self, state: EntityState) {
+        match self.authoritative {
+            Some(current) if current.tick >= state.tick => {}
+            _ => self.authoritative = Some(state),
+        }
+    }
+
+    fn take_correction(&mut self) -> Option<(EntityState, Vec<PredictedTick>)> {
+        let state = self.authoritative.take()?;
+
+        let predicted = self.history.iter().find(|t| t.tick == state.tick).copied();
+        while matches!(self.history.front(), Some(t) if t.tick <= state.tick) {
+            self.history.pop_front();
+        }
+
+        let predicted = predicted?;
+        let position: Vec2 = state.position.into();
+        let velocity: Vec2 = state.velocity.into();
+
+        if (predicted.position - position).magnitude() < Self::TOLERANCE
+            && (predicted.velocity - velocity).magnitude() < Self::TOLERANCE
+        {
+            return None;
+        }
+
+        Some((state, self.history.iter().copied().collect()))
+    }
+
+    fn update_prediction(&mut self, tick: u64, position: Vec2, velocity: Vec2) {
+        if let Some(t) = self.history.iter_mut().find(|t| t.tick == tick) {
+            t.position = position;
+            t.velocity = velocity;
+        }
+    }
+}
+impl Component for Predicted {
+    type Storage = VecStorage<Self>;
+}
+
+pub struct RemoteControlled {
+    network_id: u64,
+    owner: u32,
+    pending: VecDeque<TickInput>,
+    last_received: Option<u64>,
+    applied: Option<u64>,
+    last_input: PlayerInput,
+}
+impl RemoteControlled {
+    const MAX_PENDING: usize = 32;
+
+    pub fn new(network_id: u64, owner: u32) -> Self {
+        Self {
+            network_id,
+            owner,
+            pending: VecDeque::new(),
+            last_received: None,
+            applied: None,
+            last_input: PlayerInput::default(),
+        }
+    }
+
+    pub fn network_id(&self) -> u64 {
+        self.network_id
+    }
+
+    pub fn is_owner(&self, sender: Option<u32>) -> bool {
+        sender == Some(self.owner)
+    }
+
+    pub fn applied(&self) -> Option<u64> {
+        self.applied
+    }
+
+    pub fn enqueue(&mut self, inputs: Vec<TickInput>) {
+        for input in inputs {
+            // Anything at or before the applied tick has already been simulated, guessed or not
+            let stale = |tick: Option<u64>| matches!(tick, Some(tick) if input.tick <= tick);
+            if stale(self.last_received) || stale(self.applied) {
+                continue;
+            }
+
+            self.last_received = Some(input.tick);
+            self.pending.push_back(input);
+        }
+
+        while self.pending.len() > Self::MAX_PENDING {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Takes the input to simulate on the next tick. When the owner's input has not arrived in
+    /// time, the last one is guessed to still be held and the tick counts as simulated, so the
+    /// states sent back line up with the owner's ticks. The owner predicted the same thing unless
+    /// it let go of a key, and the input that turns up late is dropped.
+    pub fn next_input(&mut self) -> Option<PlayerInput> {
+        match self.pending.pop_front() {
+            Some(next) => {
+                self.applied = Some(next.tick);
+                self.last_input = next.input;
+            }
+            None => self.applied = Some(self.applied? + 1),
+        }
+
+        Some(self.last_input)
+    }
+}
+impl Component for RemoteControlled {
+    type Storage = VecStorage<Self>;
+}
+
+type SavedState = (
+    Entity,
+    Position,
+    Option<Velocity>,
+    Option<Acceleration>,
+    Option<Grounded>,
+);
+
+/// Rewinds each predicted entity that has received an authoritative state which disagrees with
+/// what was predicted for that tick, then replays the unacknowledged inputs on top of it. Every
+/// other entity is restored afterwards so only the predicted entity is affected by the replay.
+pub fn reconcile(world: &mut World) {
+    let corrections = {
+        let entities = world.entities();
+        let mut predicted = world.write_storage::<Predicted>();
+
+        (&entities, &mut predicted)
+            .join()
+            .filter_map(|(entity, predicted)| {
+                predicted
+                    .take_correction()
+                    .map(|(state, replay)| (entity, state, replay))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for (entity, state, replay) in corrections {
+        let saved = save_other_entities(world, entity);
+
+        {
+            let mut position = world.write_storage::<Position>();
+            let mut velocity = world.write_storage::<Velocity>();
+            let mut acceleration = world.write_storage::<Acceleration>();
+            let mut grounded = world.write_storage::<Grounded>();
+
+            if let Some(position) = position.get_mut(entity) {
+                position.0 = state.position.into();
+            }
+            if let Some(velocity) = velocity.get_mut(entity) {
+                velocity.0 = state.velocity.into();
+            }
+            if let Some(acceleration) = acceleration.get_mut(entity) {
+                acceleration.0 = state.acceleration.into();
+            }
+            if let Some(grounded) = grounded.get_mut(entity) {
+                grounded.0 = state.grounded;
+            }
+        }
+
+        for tick in replay {
+            if let Some(input) = world.write_storage::<PlayerInput>().get_mut(entity) {
+                *input = tick.input;
+            }
+
+            PlayerMovementSystem {}.run_now(world);
+            EntityMovementSystem {}.run_now(world);
+            FloorColliderSystem {}.run_now(world);
+
+            let position = world.read_storage::<Position>().get(entity).map(|p| p.0);
+            let velocity = world.read_storage::<Velocity>().get(entity).map(|v| v.0);
+            if let (Some(position), Some(velocity), Some(predicted)) = (
+                position,
+                velocity,
+                world.write_storage::<Predicted>().get_mut(entity),
+            ) {
+                predicted.update_prediction(tick.tick, position, velocity);
+            }
+        }
+
+        restore_other_entities(world, saved);
+    }
+}
+
+fn save_other_entities(world: &World, except: Entity) -> Vec<SavedState> {
+    let entities = world.entities();
+    let position = world.read_storage::<Position>();
+    let velocity = world.read_storage::<Velocity>();
+    let acceleration = world.read_storage::<Acceleration>();
+    let grounded = world.read_storage::<Grounded>();
+
+    (
+        &entities,
+        &position,
+        velocity.maybe(),
+        acceleration.maybe(),
+        grounded.maybe(),
+    )
+        .join()
+        .filter(|(entity, ..)| *entity != except)
+        .map(|(entity, position, velocity, acceleration, grounded)| {
+            (
+                entity,
+                *position,
+                velocity.copied(),
+                acceleration.copied(),
+                grounded.copied(),
+            )
+        })
+        .collect()
+}
+
+fn restore_other_entities(world: &World, saved: Vec<SavedState>) {
+    let mut position = world.write_storage::<Position>();
+    let mut velocity = world.write_storage::<Velocity>();
+    let mut acceleration = world.write_storage::<Acceleration>();
+    let mut grounded = world.write_storage::<Grounded>();
+
+    for (entity, p, v, a, g) in saved {
+        if let Some(position) = position.get_mut(entity) {
+            *position = p;
+        }
+        if let (Some(velocity), Some(v)) = (velocity.get_mut(entity), v) {
+            *velocity = v;
+        }
+        if let (Some(acceleration), Some(a)) = (acceleration.get_mut(entity), a) {
+            *acceleration = a;
+        }
+        if let (Some(grounded), Some(g)) = (grounded.get_mut(entity), g) {
+            *grounded = g;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(tick: u64, right: bool) -> TickInput {
+        TickInput {
+            tick,
+            input: PlayerInput {
+                right,
+                ..PlayerInput::default()
+            },
+        }
+    }
+
+    #[test]
+    fn nothing_is_simulated_before_the_first_input() {
+        let mut remote = RemoteControlled::new(1, 2);
+
+        assert!(remote.next_input().is_none());
+        assert_eq!(remote.applied(), None);
+    }
+
+    #[test]
+    fn a_missing_input_is_guessed_and_counted() {
+        let mut remote = RemoteControlled::new(1, 2);
+        remote.enqueue(vec![input(1, false), input(2, true)]);
+
+        assert!(!remote.next_input().unwrap().right);
+        assert!(remote.next_input().unwrap().right);
+        assert_eq!(remote.applied(), Some(2));
+
+        // Nothing has arrived for tick 3, so the last input is held for another tick
+        assert!(remote.next_input().unwrap().right);
+        assert_eq!(remote.applied(), Some(3));
+
+        // Tick 3 turns up too late to be used, but tick 4 carries on from where the guess left off
+        remote.enqueue(vec![input(3, false), input(4, false)]);
+        assert!(!remote.next_input().unwrap().right);
+        assert_eq!(remote.applied(), Some(4));
+        assert!(remote.pending.is_empty());
+    }
+}
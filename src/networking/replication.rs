@@ -0,0 +1,696 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use specs::{
+    Component, Entities, Join, ReadExpect, ReadStorage, System, SystemData, World, WorldExt, Write,
+    WriteStorage,
+};
+
+use crate::util::Vec2;
+use crate::NetworkMode;
+
+use super::components::{NetworkRecv, NetworkSend};
+use super::prediction::{Predicted, RemoteControlled};
+
+/// Steps per world unit that replicated vectors are rounded to.
+const PRECISION: f32 = 100.0;
+
+/// How many states of each entity are kept around to be used as baselines.
+const HISTORY: usize = 32;
+
+/// How many acknowledgements are remembered for each peer.
+const ACK_HISTORY: usize = 1024;
+
+/// Entities per batch, so that a batch of full states still fits in one datagram.
+const MAX_BATCH_ENTITIES: usize = 8;
+
+/// A vector rounded to a fixed precision, so it is smaller on the wire and so tiny changes are
+/// not mistaken for movement.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizedVec2(i32, i32);
+impl From<Vec2> for QuantizedVec2 {
+    fn from(vec: Vec2) -> Self {
+        Self(
+            (vec.x * PRECISION).round() as i32,
+            (vec.y * PRECISION).round() as i32,
+        )
+    }
+}
+impl From<QuantizedVec2> for Vec2 {
+    fn from(vec: QuantizedVec2) -> Self {
+        Vec2::new(vec.0 as f32 / PRECISION, vec.1 as f32 / PRECISION)
+    }
+}
+
+/// The replicated components of one entity, serialized and keyed by name.
+pub type ReplicatedEntity = BTreeMap<String, Value>;
+
+const MOTION: [&str; 3] = ["position", "velocity", "acceleration"];
+
+/// Motion is replicated alongside the registered components, but is interpolated rather than
+/// applied directly, so the `NetworkHandler` deals with it itself.
+pub fn insert_motion(
+    state: &mut ReplicatedEntity,
+    position: Vec2,
+    velocity: Vec2,
+    acceleration: Vec2,
+) {
+    for (name, vec) in MOTION.iter().zip([position, velocity, acceleration]) {
+        state.insert(
+            name.to_string(),
+            serde_json::to_value(QuantizedVec2::from(vec)).unwrap(),
+        );
+    }
+}
+
+/// Takes the motion out of a state, if all of it is there.
+pub fn take_motion(state: &mut ReplicatedEntity) -> Option<(Vec2, Vec2, Vec2)> {
+    let mut motion = MOTION.iter().filter_map(|name| {
+        let value = state.remove(*name)?;
+        serde_json::from_value::<QuantizedVec2>(value)
+            .ok()
+            .map(Vec2::from)
+    });
+
+    match (motion.next(), motion.next(), motion.next()) {
+        (Some(position), Some(velocity), Some(acceleration)) => {
+            Some((position, velocity, acceleration))
+        }
+        _ => None,
+    }
+}
+
+/// Who gets to change a replicated component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationDirection {
+    /// Only the host's values count, including for players controlled by clients.
+    HostToClients,
+    /// Whoever owns the entity sends its value, which the host passes on to everyone else.
+    OwnerToAll,
+}
+
+/// How often changes to a replicated component are sent. Until a lower priority change goes out,
+/// peers keep the last value they acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationPriority {
+    High,
+    Normal,
+}
+impl ReplicationPriority {
+    /// In sends, rather than seconds, so it scales with the send rate.
+    fn interval(self) -> u64 {
+        match self {
+            Self::High => 1,
+            Self::Normal => 2,
+        }
+    }
+}
+
+/// The parts of an entity that changed since its baseline, the last state of it every peer
+/// acknowledged. Without a baseline every part is sent.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EntityDelta {
+    pub entity_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<u64>,
+    pub components: ReplicatedEntity,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EntityBatch {
+    pub seq: u64,
+    pub timestamp: f64,
+    pub entities: Vec<EntityDelta>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EntityAck {
+    pub seqs: Vec<u64>,
+    /// Whose batches these are, when the host acknowledges them, as every client hears the host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<u32>,
+}
+
+/// Turns the state of the entities this peer sends into batches of deltas. Entities that have not
+/// changed since every peer acknowledged them are left out entirely.
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    next_seq: u64,
+    sends: u64,
+    history: HashMap<u64, VecDeque<(u64, ReplicatedEntity)>>,
+    acked: HashMap<u32, BTreeSet<u64>>,
+}
+impl DeltaEncoder {
+    pub fn acknowledge(&mut self, peer: u32, seqs: &[u64]) {
+        let acked = self.acked.entry(peer).or_default();
+        acked.extend(seqs.iter().copied().filter(|seq| *seq < self.next_seq));
+
+        while acked.len() > ACK_HISTORY {
+            acked.pop_first();
+        }
+    }
+
+    pub fn forget_peer(&mut self, peer: u32) {
+        self.acked.remove(&peer);
+    }
+
+    /// Drops the history of every entity `keep` turns down, so a despawned one is not held on to.
+    pub fn retain_entities(&mut self, mut keep: impl FnMut(u64) -> bool) {
+        self.history.retain(|&entity_id, _| keep(entity_id));
+    }
+
+    /// Only states acknowledged by every one of `peers` can be used as baselines, as the batches
+    /// go to all of them.
+    pub fn encode(
+        &mut self,
+        entities: impl IntoIterator<Item = (u64, ReplicatedEntity)>,
+        peers: &[u32],
+        priorities: &HashMap<String, ReplicationPriority>,
+        timestamp: f64,
+    ) -> Vec<EntityBatch> {
+        let sends = self.sends;
+        self.sends += 1;
+
+        let mut deltas = vec![];
+
+        for (entity_id, mut state) in entities {
+            let baseline = self.baseline(entity_id, peers);
+
+            let components = match &baseline {
+                Some((_, baseline)) => {
+                    for (name, value) in state.iter_mut() {
+                        // Motion is not registered, and always goes out
+                        let priority = priorities
+                            .get(name)
+                            .copied()
+                            .unwrap_or(ReplicationPriority::High);
+                        let due = sends.is_multiple_of(priority.interval());
+
+                        if let (false, Some(old)) = (due, baseline.get(name)) {
+                            *value = old.clone();
+                        }
+                    }
+                    // Components that are gone are not removed on peers, so are still in the
+                    // state they have
+                    for (name, value) in baseline {
+                        state.entry(name.clone()).or_insert_with(|| value.clone());
+                    }
+
+                    state
+                        .iter()
+                        .filter(|(name, value)| baseline.get(*name) != Some(*value))
+                        .map(|(name, value)| (name.clone(), value.clone()))
+                        .collect::<ReplicatedEntity>()
+                }
+                None => state.clone(),
+            };
+
+            if baseline.is_some() && components.is_empty() {
+                continue;
+            }
+
+            deltas.push((
+                state,
+                EntityDelta {
+                    entity_id,
+                    baseline: baseline.map(|(seq, _)| seq),
+                    components,
+                },
+            ));
+        }
+
+        let mut batches = vec![];
+        let mut deltas = deltas.into_iter().peekable();
+
+        while deltas.peek().is_some() {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+
+            let mut batch = EntityBatch {
+                seq,
+                timestamp,
+                entities: vec![],
+            };
+
+            for (state, delta) in deltas.by_ref().take(MAX_BATCH_ENTITIES) {
+                let history = self.history.entry(delta.entity_id).or_default();
+                history.push_back((seq, state));
+                if history.len() > HISTORY {
+                    history.pop_front();
+                }
+
+                batch.entities.push(delta);
+            }
+
+            batches.push(batch);
+        }
+
+        batches
+    }
+
+    fn baseline(&self, entity_id: u64, peers: &[u32]) -> Option<(u64, ReplicatedEntity)> {
+        if peers.is_empty() {
+            return None;
+        }
+
+        self.history
+            .get(&entity_id)?
+            .iter()
+            .rev()
+            .find(|(seq, _)| {
+                peers.iter().all(|peer| {
+                    self.acked
+                        .get(peer)
+                        .is_some_and(|acked| acked.contains(seq))
+                })
+            })
+            .cloned()
+    }
+}
+
+/// Rebuilds the full state of each entity in the batches from one peer.
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    history: HashMap<u64, VecDeque<(u64, ReplicatedEntity)>>,
+}
+impl DeltaDecoder {
+    pub fn decode(&mut self, batch: &EntityBatch) -> Vec<(u64, ReplicatedEntity)> {
+        let mut states = vec![];
+
+        for delta in &batch.entities {
+            let history = self.history.entry(delta.entity_id).or_default();
+
+            let mut state = match delta.baseline {
+                Some(baseline) => match history.iter().find(|(seq, _)| *seq == baseline) {
+                    Some((_, state)) => state.clone(),
+                    None => {
+                        println!(
+                            "Missing baseline {} for entity {}",
+                            baseline, delta.entity_id
+                        );
+                        continue;
+                    }
+                },
+                None => ReplicatedEntity::new(),
+            };
+            state.extend(delta.components.clone());
+
+            // Batches can arrive out of order, so the history is kept sorted for lookups to
+            // find the right one
+            let at = history.partition_point(|(seq, _)| *seq < batch.seq);
+            if history.get(at).is_none_or(|(seq, _)| *seq != batch.seq) {
+                history.insert(at, (batch.seq, state.clone()));
+            }
+            if history.len() > HISTORY {
+                history.pop_front();
+            }
+
+            states.push((delta.entity_id, state));
+        }
+
+        states
+    }
+
+    pub fn forget_entity(&mut self, entity_id: u64) {
+        self.history.remove(&entity_id);
+    }
+}
+
+#[derive(Debug)]
+struct IncomingComponent {
+    network_id: u64,
+    sender: Option<u32>,
+    value: Value,
+}
+
+/// Where `ComponentReplicator`s and the `NetworkHandler` exchange replicated components.
+/// Anything received for a component that nothing replicates is dropped after a frame.
+#[derive(Debug, Default)]
+pub struct ReplicationBuffer {
+    /// The latest values of the components this peer sends, by network id.
+    pub outgoing: BTreeMap<u64, ReplicatedEntity>,
+    incoming: HashMap<String, Vec<IncomingComponent>>,
+    priorities: HashMap<String, ReplicationPriority>,
+}
+impl ReplicationBuffer {
+    pub fn receive(&mut self, network_id: u64, sender: Option<u32>, state: ReplicatedEntity) {
+        for (name, value) in state {
+            self.incoming
+                .entry(name)
+                .or_default()
+                .push(IncomingComponent {
+                    network_id,
+                    sender,
+                    value,
+                });
+        }
+    }
+
+    pub fn clear_incoming(&mut self) {
+        self.incoming.clear();
+    }
+
+    pub fn priorities(&self) -> &HashMap<String, ReplicationPriority> {
+        &self.priorities
+    }
+
+    fn send(&mut self, network_id: u64, name: &str, value: &impl Serialize) {
+        match serde_json::to_value(value) {
+            Ok(value) => {
+                self.outgoing
+                    .entry(network_id)
+                    .or_default()
+                    .insert(name.to_string(), value);
+            }
+            Err(e) => println!("Could not replicate {}: {}", name, e),
+        }
+    }
+}
+
+/// Replicates one kind of component on networked entities. Add one to the dispatcher after the
+/// `NetworkHandler` for each component that should be kept in sync.
+pub struct ComponentReplicator<T> {
+    name: &'static str,
+    direction: ReplicationDirection,
+    priority: ReplicationPriority,
+    component: PhantomData<T>,
+}
+impl<T> ComponentReplicator<T> {
+    /// The name identifies the component on the wire, so has to be the same on every peer.
+    pub fn new(
+        name: &'static str,
+        direction: ReplicationDirection,
+        priority: ReplicationPriority,
+    ) -> Self {
+        Self {
+            name,
+            direction,
+            priority,
+            component: PhantomData,
+        }
+    }
+}
+impl<'a, T> System<'a> for ComponentReplicator<T>
+where
+    T: Component + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, T>,
+        ReadStorage<'a, NetworkSend>,
+        ReadStorage<'a, NetworkRecv>,
+        ReadStorage<'a, Predicted>,
+        ReadStorage<'a, RemoteControlled>,
+        ReadExpect<'a, NetworkMode>,
+        Write<'a, ReplicationBuffer>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        world
+            .write_resource::<ReplicationBuffer>()
+            .priorities
+            .insert(self.name.to_string(), self.priority);
+    }
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            mut component,
+            network_send,
+            network_recv,
+            predicted,
+            remote_controlled,
+            network_mode,
+            mut buffer,
+        ) = data;
+
+        let is_host = matches!(*network_mode, NetworkMode::Host);
+
+        for incoming in buffer.incoming.remove(self.name).unwrap_or_default() {
+            let value: T = match serde_json::from_value(incoming.value) {
+                Ok(value) => value,
+                Err(e) => {
+                    println!("Could not decode {}: {}", self.name, e);
+                    continue;
+                }
+            };
+
+            // Clients only hear from the host, so anything they receive can be trusted
+            let targets = if is_host {
+                if self.direction != ReplicationDirection::OwnerToAll {
+                    println!(
+                        "Rejected {} for entity {} from client {:?}: only the host is authoritative",
+                        self.name, incoming.network_id, incoming.sender
+                    );
+                    continue;
+                }
+
+                (&entities, &remote_controlled)
+                    .join()
+                    .filter(|c| c.1.network_id() == incoming.network_id)
+                    .filter(|c| c.1.is_owner(incoming.sender))
+                    .map(|c| c.0)
+                    .collect::<Vec<_>>()
+            } else {
+                (&entities, &network_recv)
+                    .join()
+                    .filter(|c| c.1.network_id() == incoming.network_id)
+                    .map(|c| c.0)
+                    .collect()
+            };
+
+            for entity in targets {
+                component.insert(entity, value.clone()).unwrap_or_else(|e| {
+                    println!("{}", e);
+                    None
+                });
+            }
+        }
+
+        if is_host {
+            // The host passes on what owners sent it along with its own entities
+            for (value, network_send) in (&component, &network_send).join() {
+                buffer.send(network_send.network_id(), self.name, value);
+            }
+            for (value, remote_controlled) in (&component, &remote_controlled).join() {
+                buffer.send(remote_controlled.network_id(), self.name, value);
+            }
+        } else if self.direction == ReplicationDirection::OwnerToAll {
+            for (value, predicted) in (&component, &predicted).join() {
+                buffer.send(predicted.network_id(), self.name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::{Builder, RunNow};
+
+    use super::*;
+    use crate::components::Grounded;
+
+    fn at(x: f32) -> ReplicatedEntity {
+        let mut state = ReplicatedEntity::new();
+        insert_motion(
+            &mut state,
+            Vec2::new(x, 0.0),
+            Vec2::new(1.0, 0.0),
+            Vec2::new(0.0, 0.0),
+        );
+        state
+    }
+
+    fn encode(encoder: &mut DeltaEncoder, x: f32, peers: &[u32]) -> Vec<EntityBatch> {
+        encoder.encode([(1, at(x))], peers, &HashMap::new(), 0.0)
+    }
+
+    #[test]
+    fn a_full_state_is_sent_without_a_baseline() {
+        let mut encoder = DeltaEncoder::default();
+        let batches = encode(&mut encoder, 1.0, &[2]);
+
+        assert_eq!(batches.len(), 1);
+        let delta = &batches[0].entities[0];
+        assert_eq!(delta.baseline, None);
+        assert_eq!(delta.components, at(1.0));
+
+        let mut decoder = DeltaDecoder::default();
+        assert_eq!(decoder.decode(&batches[0]), vec![(1, at(1.0))]);
+    }
+
+    #[test]
+    fn only_changes_are_sent_against_a_baseline() {
+        let mut encoder = DeltaEncoder::default();
+        let first = encode(&mut encoder, 1.0, &[2]);
+        encoder.acknowledge(2, &[first[0].seq]);
+
+        // Nothing has changed since the acknowledged state, so the entity is left out
+        assert!(encode(&mut encoder, 1.0, &[2]).is_empty());
+
+        let moved = encode(&mut encoder, 2.0, &[2]);
+        let delta = &moved[0].entities[0];
+        assert_eq!(delta.baseline, Some(first[0].seq));
+        assert_eq!(
+            delta.components.keys().collect::<Vec<_>>(),
+            vec!["position"]
+        );
+
+        let mut decoder = DeltaDecoder::default();
+        decoder.decode(&first[0]);
+        assert_eq!(decoder.decode(&moved[0]), vec![(1, at(2.0))]);
+    }
+
+    #[test]
+    fn a_new_peer_gets_full_states() {
+        let mut encoder = DeltaEncoder::default();
+        let first = encode(&mut encoder, 1.0, &[2]);
+        encoder.acknowledge(2, &[first[0].seq]);
+
+        // Peer 3 has acknowledged nothing, so there is no baseline both peers have
+        let batches = encode(&mut encoder, 1.0, &[2, 3]);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].entities[0].baseline, None);
+        assert_eq!(batches[0].entities[0].components, at(1.0));
+    }
+
+    #[test]
+    fn a_batch_can_arrive_after_a_newer_one() {
+        let mut encoder = DeltaEncoder::default();
+        let first = encode(&mut encoder, 1.0, &[2]);
+        encoder.acknowledge(2, &[first[0].seq]);
+        let second = encode(&mut encoder, 2.0, &[2]);
+        let third = encode(&mut encoder, 3.0, &[2]);
+        encoder.acknowledge(2, &[third[0].seq]);
+        let fourth = encode(&mut encoder, 4.0, &[2]);
+        assert_eq!(fourth[0].entities[0].baseline, Some(third[0].seq));
+
+        let mut decoder = DeltaDecoder::default();
+        assert_eq!(decoder.decode(&first[0]), vec![(1, at(1.0))]);
+        assert_eq!(decoder.decode(&third[0]), vec![(1, at(3.0))]);
+        // The late batch still has its baseline, and does not get in the way of later ones
+        assert_eq!(decoder.decode(&second[0]), vec![(1, at(2.0))]);
+        assert_eq!(decoder.decode(&fourth[0]), vec![(1, at(4.0))]);
+    }
+
+    #[test]
+    fn the_baseline_is_the_newest_state_every_peer_has() {
+        let mut encoder = DeltaEncoder::default();
+        let seqs = (1..=3)
+            .map(|x| encode(&mut encoder, x as f32, &[]).remove(0).seq)
+            .collect::<Vec<_>>();
+
+        // Acknowledgements can arrive in any order
+        encoder.acknowledge(2, &[seqs[1]]);
+        encoder.acknowledge(2, &[seqs[0]]);
+        encoder.acknowledge(3, &[seqs[0]]);
+        assert_eq!(encoder.baseline(1, &[2]).unwrap().0, seqs[1]);
+        assert_eq!(encoder.baseline(1, &[2, 3]).unwrap().0, seqs[0]);
+
+        encoder.acknowledge(3, &[seqs[2]]);
+        assert_eq!(encoder.baseline(1, &[2, 3]).unwrap().0, seqs[0]);
+
+        // Sequence numbers that were never sent are not taken as acknowledgements
+        encoder.acknowledge(2, &[seqs[2] + 1]);
+        assert_eq!(encoder.baseline(1, &[2]).unwrap().0, seqs[1]);
+    }
+
+    #[test]
+    fn lower_priorities_are_sent_less_often() {
+        let priorities = HashMap::from([("grounded".to_string(), ReplicationPriority::Normal)]);
+        let with_grounded = |x: f32, grounded: bool| {
+            let mut state = at(x);
+            state.insert("grounded".to_string(), Value::Bool(grounded));
+            state
+        };
+
+        let mut encoder = DeltaEncoder::default();
+        let mut send = |x: f32, grounded: bool| {
+            let mut batches =
+                encoder.encode([(1, with_grounded(x, grounded))], &[2], &priorities, 0.0);
+            let batch = batches.remove(0);
+            encoder.acknowledge(2, &[batch.seq]);
+            batch.entities.into_iter().next().unwrap().components
+        };
+
+        // A full state has everything, whatever the priority
+        assert_eq!(send(1.0, true), with_grounded(1.0, true));
+
+        // Grounded is held back on odd sends, while motion goes out on every one
+        let held_back = send(2.0, false);
+        assert!(held_back.contains_key("position"));
+        assert!(!held_back.contains_key("grounded"));
+
+        let due = send(2.0, false);
+        assert_eq!(due.get("grounded"), Some(&Value::Bool(false)));
+        assert!(!due.contains_key("position"));
+    }
+
+    fn host_with_client_player() -> World {
+        let mut world = World::new();
+        world.insert(NetworkMode::Host);
+        world.register::<Grounded>();
+        world.register::<RemoteControlled>();
+        world
+            .create_entity()
+            .with(Grounded(true))
+            .with(RemoteControlled::new(5, 2))
+            .build();
+        world
+    }
+
+    fn grounded(world: &World) -> bool {
+        (&world.read_storage::<Grounded>()).join().next().unwrap().0
+    }
+
+    fn receive(world: &World, sender: u32) {
+        let state = ReplicatedEntity::from([("grounded".to_string(), Value::Bool(false))]);
+        world
+            .write_resource::<ReplicationBuffer>()
+            .receive(5, Some(sender), state);
+    }
+
+    #[test]
+    fn the_host_only_takes_owned_components_from_their_owner() {
+        let mut world = host_with_client_player();
+        let mut replicator = ComponentReplicator::<Grounded>::new(
+            "grounded",
+            ReplicationDirection::OwnerToAll,
+            ReplicationPriority::High,
+        );
+        System::setup(&mut replicator, &mut world);
+
+        receive(&world, 3);
+        replicator.run_now(&world);
+        assert!(grounded(&world));
+
+        receive(&world, 2);
+        replicator.run_now(&world);
+        assert!(!grounded(&world));
+
+        // What the owner sent is passed on to everyone else
+        let buffer = world.read_resource::<ReplicationBuffer>();
+        assert_eq!(buffer.outgoing[&5]["grounded"], Value::Bool(false));
+        assert_eq!(buffer.priorities()["grounded"], ReplicationPriority::High);
+    }
+
+    #[test]
+    fn the_host_ignores_clients_for_its_own_components() {
+        let mut world = host_with_client_player();
+        let mut replicator = ComponentReplicator::<Grounded>::new(
+            "grounded",
+            ReplicationDirection::HostToClients,
+            ReplicationPriority::Normal,
+        );
+        System::setup(&mut replicator, &mut world);
+
+        receive(&world, 2);
+        replicator.run_now(&world);
+        assert!(grounded(&world));
+    }
+}
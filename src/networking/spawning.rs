@@ -0,0 +1,567 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde_derive::{Deserialize, Serialize};
+use specs::{
+    Builder, Entities, Join, LazyUpdate, Read, ReadExpect, ReadStorage, System, Write, WriteStorage,
+};
+
+use crate::{
+    components::{Acceleration, PlayerName, Position, Velocity},
+    prefabs::{self, Prefab},
+    resources::{GameState, Lobby},
+    util::Vec2,
+    NetworkMode,
+};
+
+use super::authority::{set_owner, Authority};
+use super::components::{decode, NetworkInbox, NetworkRecv, NetworkSend, UpdateEntity};
+use super::interpolation::SnapshotBuffer;
+use super::prediction::{Predicted, RemoteControlled};
+use super::systems::{ConnectionStatus, Message, PeerEvent, PeerJoined};
+use super::validation::MovementValidator;
+
+pub struct Incrementor {
+    value: u64,
+}
+impl Incrementor {
+    pub fn starting_at(value: u64) -> Self {
+        Self { value }
+    }
+}
+impl Iterator for Incrementor {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.value;
+        self.value += 1;
+        Some(current)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Roster {
+    pub players: Vec<u64>,
+    pub entities: Vec<u64>,
+    pub names: HashMap<u64, String>,
+}
+
+/// Everything a peer needs to catch up with the host, sent when a client rejoins the room. It is
+/// split up so each part fits in a datagram: the first has the roster and where the players are,
+/// and each of the rest one spawned entity along with where it is.
+#[derive(Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roster: Option<Roster>,
+    pub spawns: Vec<SpawnEntity>,
+    pub updates: Vec<UpdateEntity>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SpawnEntity {
+    pub network_id: u64,
+    pub prefab: Prefab,
+    pub owner: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DespawnEntity {
+    pub network_id: u64,
+}
+
+/// Requests for networked entities to be created or removed on every peer. Only the host acts on
+/// them; it allocates the network ids and tells everyone else.
+#[derive(Default)]
+pub struct NetworkSpawner {
+    spawn: Vec<Prefab>,
+    despawn: Vec<u64>,
+}
+impl NetworkSpawner {
+    pub fn spawn(&mut self, prefab: Prefab) {
+        self.spawn.push(prefab);
+    }
+
+    pub fn despawn(&mut self, network_id: u64) {
+        self.despawn.push(network_id);
+    }
+}
+
+/// Keeps who and what is in the room the same on every peer. The host spawns and despawns
+/// networked entities, creates players as clients join, and regularly sends everyone the roster
+/// so they can catch up on anything they missed.
+pub struct SpawnHandler {
+    roster_changed: bool,
+    snapshot_requested: bool,
+    network_ids: Incrementor,
+    spawned: HashMap<u64, Prefab>,
+    client_id: u32,
+}
+impl SpawnHandler {
+    pub fn new(client_id: u32) -> Self {
+        Self {
+            roster_changed: true,
+            snapshot_requested: false,
+            // Players go by their client id, so spawned entities are numbered above every one
+            network_ids: Incrementor::starting_at(u32::MAX as u64 + 1),
+            spawned: HashMap::new(),
+            client_id,
+        }
+    }
+}
+impl SpawnHandler {
+    const ROSTER_INTERVAL: u64 = 60;
+}
+impl<'a> System<'a> for SpawnHandler {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, NetworkSend>,
+        ReadStorage<'a, NetworkRecv>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Acceleration>,
+        ReadStorage<'a, Predicted>,
+        ReadStorage<'a, RemoteControlled>,
+        WriteStorage<'a, PlayerName>,
+        WriteStorage<'a, Authority>,
+        WriteStorage<'a, SnapshotBuffer>,
+        WriteStorage<'a, MovementValidator>,
+        Read<'a, LazyUpdate>,
+        Write<'a, NetworkSpawner>,
+        Read<'a, ConnectionStatus>,
+        Write<'a, Lobby>,
+        Read<'a, GameState>,
+        ReadExpect<'a, NetworkMode>,
+        Write<'a, NetworkInbox>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            network_send,
+            network_recv,
+            position,
+            velocity,
+            acceleration,
+            predicted,
+            remote_controlled,
+            mut player_name,
+            mut authority,
+            mut snapshot_buffer,
+            mut movement_validator,
+            lazy,
+            mut spawner,
+            connection_status,
+            mut lobby,
+            game_state,
+            network_mode,
+            mut inbox,
+        ) = data;
+
+        let is_host = matches!(*network_mode, NetworkMode::Host);
+
+        if let NetworkMode::Client = *network_mode {
+            if !spawner.spawn.is_empty() || !spawner.despawn.is_empty() {
+                println!("Only the host can spawn or despawn networked entities");
+                spawner.spawn.clear();
+                spawner.despawn.clear();
+            }
+        }
+
+        for prefab in spawner.spawn.drain(..) {
+            let network_id = self.network_ids.next().unwrap();
+
+            prefab
+                .build(lazy.create_entity(&entities))
+                .with(NetworkSend::new(network_id))
+                .with(Authority::new(self.client_id, self.client_id))
+                .build();
+
+            inbox.send(Message::new(
+                "entity/spawn".to_string(),
+                SpawnEntity {
+                    network_id,
+                    prefab: prefab.clone(),
+                    owner: self.client_id,
+                },
+            ));
+
+            self.spawned.insert(network_id, prefab);
+            self.roster_changed = true;
+        }
+
+        for network_id in spawner.despawn.drain(..) {
+            if self.spawned.remove(&network_id).is_none() {
+                continue;
+            }
+
+            for (entity, _) in (&entities, &network_send)
+                .join()
+                .filter(|c| c.1.network_id() == network_id)
+            {
+                entities
+                    .delete(entity)
+                    .unwrap_or_else(|e| println!("{}", e));
+            }
+
+            inbox.send(Message::new(
+                "entity/despawn".to_string(),
+                DespawnEntity { network_id },
+            ));
+            self.roster_changed = true;
+        }
+
+        if is_host
+            && (self.roster_changed
+                || self.snapshot_requested
+                || game_state.tick % Self::ROSTER_INTERVAL == 0)
+        {
+            self.roster_changed = false;
+
+            let players = (&network_send)
+                .join()
+                .map(|c| c.network_id())
+                .filter(|id| !self.spawned.contains_key(id))
+                .chain((&remote_controlled).join().map(|c| c.network_id()))
+                .collect();
+            let entities = self.spawned.keys().copied().collect();
+            let names = (&network_send, &player_name)
+                .join()
+                .map(|(c, name)| (c.network_id(), name.0.clone()))
+                .chain(
+                    (&remote_controlled, &player_name)
+                        .join()
+                        .map(|(c, name)| (c.network_id(), name.0.clone())),
+                )
+                .collect();
+            let roster = Roster {
+                players,
+                entities,
+                names,
+            };
+
+            let spawns = self
+                .spawned
+                .iter()
+                .map(|(&network_id, prefab)| SpawnEntity {
+                    network_id,
+                    prefab: prefab.clone(),
+                    owner: (&network_send, &authority)
+                        .join()
+                        .find(|c| c.0.network_id() == network_id)
+                        .map_or(self.client_id, |c| c.1.owner()),
+                })
+                .collect::<Vec<_>>();
+
+            if self.snapshot_requested {
+                self.snapshot_requested = false;
+
+                let mut updates = (
+                    &position,
+                    &velocity,
+                    &acceleration,
+                    network_send.maybe(),
+                    remote_controlled.maybe(),
+                )
+                    .join()
+                    .filter_map(|(position, velocity, acceleration, send, controlled)| {
+                        let network_id = send
+                            .map(|c| c.network_id())
+                            .or_else(|| controlled.map(|c| c.network_id()))?;
+
+                        let update = UpdateEntity::new(
+                            network_id,
+                            game_state.elapsed,
+                            position.0,
+                            velocity.0,
+                            acceleration.0,
+                        );
+                        Some((network_id, update))
+                    })
+                    .collect::<HashMap<_, _>>();
+
+                // The parts can arrive in any order, so each keeps an entity's spawn and update
+                // together
+                let players = roster
+                    .players
+                    .iter()
+                    .filter_map(|network_id| updates.remove(network_id))
+                    .collect();
+                inbox.send(Message::new(
+                    "room/snapshot".to_string(),
+                    RoomSnapshot {
+                        roster: Some(roster),
+                        spawns: vec![],
+                        updates: players,
+                    },
+                ));
+                for spawn in spawns {
+                    let update = updates.remove(&spawn.network_id);
+                    inbox.send(Message::new(
+                        "room/snapshot".to_string(),
+                        RoomSnapshot {
+                            roster: None,
+                            spawns: vec![spawn],
+                            updates: update.into_iter().collect(),
+                        },
+                    ));
+                }
+            } else {
+                inbox.send(Message::new("room/roster".to_string(), roster));
+
+                // Spawns are resent with the roster so peers that missed one, or joined later,
+                // catch up
+                for spawn in spawns {
+                    inbox.send(Message::new("entity/spawn".to_string(), spawn));
+                }
+            }
+
+            if lobby.started {
+                inbox.send(Message::new("room/start".to_string(), ()));
+            }
+        }
+
+        // Clients wait in the lobby for the host to start, which is repeated with the roster for
+        // anyone who joins afterwards. The host's player only exists once the level is loaded at
+        // the end of this tick, so the roster that goes with the start is sent on the next.
+        if is_host && !lobby.started {
+            if let ConnectionStatus::Connected(peers) = &*connection_status {
+                if lobby.ready(peers) {
+                    lobby.started = true;
+                    self.roster_changed = true;
+                }
+            }
+        }
+
+        let mut spawned_this_tick = HashSet::new();
+        let mut unpacked = VecDeque::from(inbox.received(&[
+            "room/roster",
+            "room/start",
+            "room/snapshot",
+            "room/closed",
+            "entity/spawn",
+            "entity/despawn",
+            "peer/join",
+            "peer/rejoin",
+            "peer/leave",
+        ]));
+
+        while let Some(msg) = unpacked.pop_front() {
+            let sender = msg.sender;
+
+            match msg.msg_type.as_str() {
+                "room/roster" if !is_host => {
+                    let msg: Roster = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    for (entity, network_recv) in (&entities, &network_recv).join() {
+                        if !msg.players.contains(&network_recv.network_id())
+                            && !msg.entities.contains(&network_recv.network_id())
+                        {
+                            entities
+                                .delete(entity)
+                                .unwrap_or_else(|e| println!("{}", e));
+                        }
+                    }
+
+                    for (network_recv, name) in (&network_recv, &mut player_name).join() {
+                        if let Some(new_name) = msg.names.get(&network_recv.network_id()) {
+                            if name.0 != *new_name {
+                                name.0 = new_name.clone();
+                            }
+                        }
+                    }
+
+                    let known = (&network_recv)
+                        .join()
+                        .map(|c| c.network_id())
+                        .chain((&predicted).join().map(|c| c.network_id()))
+                        .collect::<Vec<_>>();
+
+                    for &network_id in msg.players.iter().filter(|id| !known.contains(id)) {
+                        if !spawned_this_tick.insert(network_id) {
+                            continue;
+                        }
+
+                        let name = msg
+                            .names
+                            .get(&network_id)
+                            .cloned()
+                            .unwrap_or_else(|| format!("Player {}", network_id));
+                        println!("{} joined", name);
+
+                        prefabs::player(
+                            lazy.create_entity(&entities),
+                            Vec2::new(0.0, 0.0),
+                            prefabs::REMOTE_PLAYER_COLOUR,
+                        )
+                        .with(SnapshotBuffer::new())
+                        .with(NetworkRecv::new(network_id))
+                        .with(PlayerName(name))
+                        .build();
+                    }
+                }
+                "room/start" if sender.is_some() && !is_host => lobby.started = true,
+                "entity/spawn" if !is_host => {
+                    let msg: SpawnEntity = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    // Spawns are resent with the roster, which also fixes up any missed handoffs
+                    let existing = (&entities, &network_recv, (&authority).maybe())
+                        .join()
+                        .find(|c| c.1.network_id() == msg.network_id)
+                        .map(|c| (c.0, c.2.map(|authority| authority.owner())));
+                    if let Some((entity, owner)) = existing {
+                        if owner.is_some_and(|owner| owner != msg.owner) {
+                            set_owner(
+                                entity,
+                                msg.owner,
+                                self.client_id,
+                                &mut authority,
+                                &mut snapshot_buffer,
+                                &mut movement_validator,
+                            );
+                        }
+                        continue;
+                    }
+                    if !spawned_this_tick.insert(msg.network_id) {
+                        continue;
+                    }
+
+                    let builder = msg
+                        .prefab
+                        .build(lazy.create_entity(&entities))
+                        .with(NetworkRecv::new(msg.network_id))
+                        .with(Authority::new(msg.owner, self.client_id));
+                    if msg.owner == self.client_id {
+                        builder.build();
+                    } else {
+                        builder.with(SnapshotBuffer::new()).build();
+                    }
+                }
+                "entity/despawn" if !is_host => {
+                    let msg: DespawnEntity = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    for (entity, _) in (&entities, &network_recv)
+                        .join()
+                        .filter(|c| c.1.network_id() == msg.network_id)
+                    {
+                        entities
+                            .delete(entity)
+                            .unwrap_or_else(|e| println!("{}", e));
+                    }
+                }
+                "room/snapshot" if !is_host => {
+                    let msg: RoomSnapshot = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    // The snapshot is handled as the individual messages it stands in for, the
+                    // updates by the `AuthorityHandler` like any other
+                    let message = |msg_type: &str, data| {
+                        let mut msg = Message::new(msg_type.to_string(), data);
+                        msg.sender = sender;
+                        msg
+                    };
+
+                    if let Some(roster) = msg.roster {
+                        unpacked.push_back(message(
+                            "room/roster",
+                            serde_json::to_value(roster).unwrap(),
+                        ));
+                    }
+                    for spawn in msg.spawns {
+                        unpacked.push_back(message(
+                            "entity/spawn",
+                            serde_json::to_value(spawn).unwrap(),
+                        ));
+                    }
+                    for update in msg.updates {
+                        inbox.receive(message(
+                            "entity/update",
+                            serde_json::to_value(update).unwrap(),
+                        ));
+                    }
+                }
+                "peer/join" | "peer/rejoin" if sender.is_none() && is_host => {
+                    let rejoined = msg.msg_type == "peer/rejoin";
+                    let msg: PeerJoined = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    let network_id = msg.client_id as u64;
+
+                    // A client that rejoins in time takes back the player it left behind
+                    let existing = (&entities, &remote_controlled)
+                        .join()
+                        .find(|c| c.1.network_id() == network_id)
+                        .map(|c| c.0);
+
+                    if let Some(entity) = existing {
+                        player_name
+                            .insert(entity, PlayerName(msg.name))
+                            .unwrap_or_else(|e| {
+                                println!("{}", e);
+                                None
+                            });
+                    } else if spawned_this_tick.insert(network_id) {
+                        prefabs::controlled_player(
+                            lazy.create_entity(&entities),
+                            Vec2::new(0.0, 0.0),
+                            prefabs::REMOTE_PLAYER_COLOUR,
+                        )
+                        .with(RemoteControlled::new(network_id, msg.client_id))
+                        .with(PlayerName(msg.name))
+                        .build();
+                    }
+
+                    self.roster_changed = true;
+                    if rejoined {
+                        self.snapshot_requested = true;
+                    }
+                }
+                "room/closed" if sender.is_none() => {
+                    for (entity, _) in (&entities, &network_recv).join() {
+                        entities
+                            .delete(entity)
+                            .unwrap_or_else(|e| println!("{}", e));
+                    }
+                }
+                "peer/leave" if sender.is_none() => {
+                    let msg: PeerEvent = match decode(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    let network_id = msg.client_id as u64;
+
+                    for (entity, _) in (&entities, &remote_controlled)
+                        .join()
+                        .filter(|c| c.1.network_id() == network_id)
+                    {
+                        entities
+                            .delete(entity)
+                            .unwrap_or_else(|e| println!("{}", e));
+                    }
+
+                    for (entity, _) in (&entities, &network_recv)
+                        .join()
+                        .filter(|c| c.1.network_id() == network_id)
+                    {
+                        entities
+                            .delete(entity)
+                            .unwrap_or_else(|e| println!("{}", e));
+                    }
+
+                    self.roster_changed = true;
+                }
+                _ => {}
+            }
+        }
+    }
+}
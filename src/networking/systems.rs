@@ -1,53 +1,128 @@
+use crate::networking::conditions::{Conditioned, Link, NetworkConditions};
+use crate::networking::crypto::{Identity, Opener, Sealer};
 use serde::{Deserialize, Serialize};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, SocketAddr};
+use std::net::{AddrParseError, IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
-use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs, UdpSocket};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+const RECONNECT_GRACE: Duration = Duration::from_secs(30);
+const RESEND_INTERVAL: Duration = Duration::from_millis(250);
+/// How many messages from the game can be waiting to go out to a peer before the oldest are
+/// dropped. A frame can send several, so this covers a few seconds of a stalled connection.
+const BROADCAST_CAPACITY: usize = 256;
+
+/// The largest datagram sent to or accepted from a peer. Ethernet carries 1500 bytes, less the IP
+/// and UDP headers, and this leaves room for tunnels along the way so nothing is fragmented.
+pub const MAX_DATAGRAM: usize = 1400;
+
+/// Message types that have to arrive, so are resent until the peer acknowledges them.
+const RELIABLE_MESSAGES: [&str; 12] = [
+    "chat/message",
+    "gesture/ping",
+    "gesture/emote",
+    "authority/request",
+    "authority/release",
+    "authority/grant",
+    "room/snapshot",
+    "lockstep/start",
+    "lockstep/input",
+    "lockstep/frame",
+    "lockstep/hash",
+    "lockstep/desync",
+];
 
 #[derive(Debug)]
-pub enum IoOrSerdeError {
+pub enum NetworkError {
     IoError(io::Error),
     SerdeError(serde_json::Error),
+    AddressError(AddrParseError),
+    NotConnected,
+    JoinRejected(String),
+    MissingHostData,
+    UnexpectedMessage(String),
+    HandshakeFailed(u32),
+    KeyExchange(u32),
 }
-impl std::error::Error for IoOrSerdeError {}
-impl Display for IoOrSerdeError {
+impl std::error::Error for NetworkError {}
+impl Display for NetworkError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::IoError(e) => write!(f, "{}", e),
             Self::SerdeError(e) => write!(f, "{}", e),
+            Self::AddressError(e) => write!(f, "{}", e),
+            Self::NotConnected => write!(f, "Not connected to the rendezvous server"),
+            Self::JoinRejected(reason) => write!(f, "{}", reason),
+            Self::MissingHostData => write!(f, "The rendezvous server did not say who the host is"),
+            Self::UnexpectedMessage(msg_type) => write!(f, "Did not expect a {} message", msg_type),
+            Self::HandshakeFailed(client_id) => {
+                write!(f, "Could not open a connection to client {}", client_id)
+            }
+            Self::KeyExchange(client_id) => {
+                write!(
+                    f,
+                    "Could not agree on a session key with client {}",
+                    client_id
+                )
+            }
         }
     }
 }
-impl From<io::Error> for IoOrSerdeError {
+impl From<io::Error> for NetworkError {
     fn from(e: io::Error) -> Self {
         Self::IoError(e)
     }
 }
-impl From<serde_json::Error> for IoOrSerdeError {
+impl From<serde_json::Error> for NetworkError {
     fn from(e: serde_json::Error) -> Self {
         Self::SerdeError(e)
     }
 }
+impl From<AddrParseError> for NetworkError {
+    fn from(e: AddrParseError) -> Self {
+        Self::AddressError(e)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     #[serde(rename = "type")]
     pub msg_type: String,
     pub data: Value,
+    #[serde(skip)]
+    pub sender: Option<u32>,
 }
 impl Message {
     pub fn new<'a>(msg_type: String, data: impl Serialize) -> Self {
         Self {
             msg_type,
             data: serde_json::to_value(data).unwrap(),
+            sender: None,
         }
     }
+
+    /// A copy that is safe to print, with any room password hidden.
+    pub fn redacted(&self) -> Self {
+        let mut msg = self.clone();
+        if let Some(password) = msg.data.get_mut("password").filter(|p| !p.is_null()) {
+            *password = Value::from("(hidden)");
+        }
+        msg
+    }
 }
 impl TryInto<Value> for Message {
     type Error = serde_json::Error;
@@ -65,8 +140,12 @@ pub struct ClientJoinedResponse {
 #[derive(Serialize, Deserialize)]
 pub struct CreateRoomRequest {
     pub max_clients: usize,
-    pub send_port: u16,
-    pub recv_port: u16,
+    pub port: u16,
+    pub public_key: String,
+    pub name: String,
+    pub level: String,
+    pub public: bool,
+    pub password: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -77,21 +156,39 @@ pub struct CreateRoomResponse {
 #[derive(Serialize, Deserialize)]
 pub struct JoinRoomRequest {
     pub room_id: String,
-    pub send_port: u16,
-    pub recv_port: u16,
+    pub port: u16,
+    pub public_key: String,
+    pub name: String,
+    pub password: Option<String>,
+}
+
+/// A public room, as advertised by the rendezvous server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomInfo {
+    pub room_id: String,
+    pub players: usize,
+    pub max_clients: usize,
+    pub level: String,
+    pub has_password: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoomListResponse {
+    pub rooms: Vec<RoomInfo>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct NetworkData {
     ip: String,
-    send_port: u16,
-    recv_port: u16,
+    port: u16,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ClientData {
     client_id: u32,
     network_data: NetworkData,
+    public_key: String,
+    name: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -100,10 +197,161 @@ pub struct JoinRoomResponse {
     pub room_id: String,
     pub msg: Option<String>,
     pub host_data: Option<ClientData>,
+    pub reconnect_token: Option<String>,
 }
 
 type JoinRoomNotification = ClientData;
 
+#[derive(Serialize, Deserialize)]
+pub struct RejoinRoomRequest {
+    pub room_id: String,
+    pub client_id: u32,
+    pub reconnect_token: String,
+    pub port: u16,
+    pub public_key: String,
+    pub name: String,
+}
+
+type RejoinRoomResponse = JoinRoomResponse;
+type RejoinRoomNotification = ClientData;
+
+/// What a client needs to reclaim its slot in a room after losing its connection.
+#[derive(Debug, Clone)]
+struct ReconnectToken {
+    room_id: String,
+    room_host: u32,
+    client_id: u32,
+    token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PeerEvent {
+    pub client_id: u32,
+}
+
+type LeaveRoomNotification = PeerEvent;
+
+#[derive(Serialize, Deserialize)]
+pub struct PeerJoined {
+    pub client_id: u32,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConnectionError {
+    pub msg: String,
+}
+
+/// A message passed between two peers by the rendezvous server, for when they cannot reach each
+/// other directly. `client_id` is the recipient when sending and the sender when receiving. The
+/// payload is the same sealed packet that would have gone over UDP, base64 encoded, so the server
+/// cannot read or forge it.
+#[derive(Serialize, Deserialize)]
+pub struct RelayMessage {
+    pub client_id: u32,
+    pub payload: String,
+}
+
+/// How far along joining a room we are, as seen by the game.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum ConnectionStatus {
+    #[default]
+    Connecting,
+    WaitingForPeer,
+    Connected(Vec<u32>),
+    Lost(String),
+}
+
+/// Echoed back by the peer as a "connection/keep-alive-ack", to measure the round trip time.
+#[derive(Serialize, Deserialize)]
+pub struct KeepAlive {
+    pub sent_at: f64,
+}
+
+/// How the connection to one peer is doing, reported to the game every second.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerStats {
+    pub client_id: u32,
+    pub name: String,
+    pub relayed: bool,
+    pub rtt: Option<f64>,
+    /// Bytes per second, including encryption overhead.
+    pub sent: f64,
+    pub received: f64,
+    /// The fraction of the peer's packets that never arrived.
+    pub loss: f64,
+    /// Filled in by the game, as the portal knows nothing about entities.
+    #[serde(default)]
+    pub snapshot_depth: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub peers: Vec<PeerStats>,
+}
+
+/// Running totals for the traffic to and from one peer, shared with the task that sends to it.
+#[derive(Debug, Default, Clone, Copy)]
+struct TrafficCounters {
+    bytes_sent: u64,
+    bytes_received: u64,
+    packets_received: u64,
+    /// The highest packet counter seen from the peer, used to tell how many went missing.
+    highest_received: Option<u64>,
+}
+
+/// Seconds since the epoch. Only ever compared with itself, as keep-alives are echoed back.
+fn timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs_f64())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReliableMessage {
+    pub seq: u64,
+    pub message: Message,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReliableAck {
+    pub seq: u64,
+}
+
+/// Reliable messages sent to a peer that it has not acknowledged yet.
+#[derive(Debug, Default)]
+struct ReliableOutbox {
+    next_seq: u64,
+    pending: BTreeMap<u64, Message>,
+}
+
+/// Which reliable messages from a peer have already been passed on, so resends can be dropped.
+#[derive(Debug, Default)]
+struct ReliableInbox {
+    delivered_below: u64,
+    delivered: BTreeSet<u64>,
+}
+impl ReliableInbox {
+    /// Returns false if the message was delivered before.
+    fn deliver(&mut self, seq: u64) -> bool {
+        if seq < self.delivered_below || !self.delivered.insert(seq) {
+            return false;
+        }
+
+        while self.delivered.remove(&self.delivered_below) {
+            self.delivered_below += 1;
+        }
+
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaveRoomRequest {
+    pub room_id: String,
+}
+
 fn print_err<O, E>(e: E) -> O
 where
     O: Default,
@@ -119,11 +367,352 @@ struct RendezvousConnector {
     pub client_id: u32,
 }
 
+/// Sends a peer's packets straight to it, or through the rendezvous server once hole punching has
+/// given up.
+#[derive(Debug, Clone)]
+struct PeerLink {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    client_id: u32,
+    relay: watch::Receiver<Option<mpsc::Sender<Message>>>,
+}
+impl Link for PeerLink {
+    async fn send(&self, packet: Vec<u8>) {
+        let relay = self.relay.borrow().clone();
+
+        if let Some(relay) = relay {
+            let msg = Message::new(
+                "relay/send".to_string(),
+                RelayMessage {
+                    client_id: self.client_id,
+                    payload: base64::encode(&packet),
+                },
+            );
+
+            relay.send(msg).await.unwrap_or_else(print_err);
+            return;
+        }
+
+        self.socket
+            .send_to(&packet, self.addr)
+            .await
+            .unwrap_or_else(print_err);
+    }
+}
+
 #[derive(Debug)]
 pub struct PeerConnection {
     pub tx: mpsc::Sender<Message>,
     pub client_id: u32,
-    pub peer_addr: SocketAddr,
+    pub name: String,
+    pub addr: SocketAddr,
+    pub last_heard: Instant,
+    pub established: bool,
+    pub relayed: bool,
+    started: Instant,
+    handshake: JoinHandle<()>,
+    relay: watch::Sender<Option<mpsc::Sender<Message>>>,
+    sealer: Arc<std::sync::Mutex<Sealer>>,
+    opener: Opener,
+    outbox: Arc<std::sync::Mutex<ReliableOutbox>>,
+    inbox: ReliableInbox,
+    counters: Arc<std::sync::Mutex<TrafficCounters>>,
+    last_report: (Instant, TrafficCounters),
+    rtt: Option<f64>,
+    tasks: Vec<JoinHandle<()>>,
+}
+impl PeerConnection {
+    async fn connect(
+        client_data: &ClientData,
+        (sealer, opener): (Sealer, Opener),
+        socket: &Arc<UdpSocket>,
+        conditions: NetworkConditions,
+        broadcast_tx: &broadcast::Sender<Message>,
+    ) -> Result<Self, NetworkError> {
+        let client_id = client_data.client_id;
+        let ip: IpAddr = client_data.network_data.ip.parse()?;
+        let addr = SocketAddr::new(ip, client_data.network_data.port);
+
+        let socket = socket.clone();
+
+        let (tx, mut rx) = mpsc::channel::<Message>(100);
+        let mut tasks = vec![];
+
+        // Both sides punch until they get an acknowledgement back, which proves that datagrams
+        // make it through in both directions
+        let handshake = {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let msg = Message::new("connection/hole-punch".to_string(), ());
+                    if tx.send(msg).await.is_err() {
+                        break;
+                    }
+
+                    tokio::time::sleep(PUNCH_INTERVAL).await;
+                }
+            })
+        };
+
+        {
+            let tx = tx.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    // Keeps the NAT mapping open and lets the peer know we are still here
+                    let msg = Message::new(
+                        "connection/keep-alive".to_string(),
+                        KeepAlive {
+                            sent_at: timestamp(),
+                        },
+                    );
+                    if tx.send(msg).await.is_err() {
+                        break;
+                    }
+
+                    tokio::time::sleep(KEEP_ALIVE_INTERVAL).await;
+                }
+            }));
+        }
+
+        let (relay, relay_rx) = watch::channel::<Option<mpsc::Sender<Message>>>(None);
+        let link = Conditioned::new(
+            PeerLink {
+                socket,
+                addr,
+                client_id,
+                relay: relay_rx,
+            },
+            conditions,
+        );
+        let sealer = Arc::new(std::sync::Mutex::new(sealer));
+        let outbox = Arc::new(std::sync::Mutex::new(ReliableOutbox::default()));
+        let counters = Arc::new(std::sync::Mutex::new(TrafficCounters::default()));
+
+        {
+            let sealer = sealer.clone();
+            let outbox = outbox.clone();
+            let counters = counters.clone();
+            tasks.push(tokio::spawn(async move {
+                while let Some(val) = rx.recv().await {
+                    let msg_type = val.msg_type.clone();
+                    let (val, seq) = if RELIABLE_MESSAGES.contains(&msg_type.as_str()) {
+                        let mut outbox = outbox.lock().unwrap();
+                        let seq = outbox.next_seq;
+                        outbox.next_seq += 1;
+
+                        let msg = Message::new(
+                            "reliable/message".to_string(),
+                            ReliableMessage { seq, message: val },
+                        );
+                        outbox.pending.insert(seq, msg.clone());
+                        (msg, Some(seq))
+                    } else {
+                        (val, None)
+                    };
+
+                    let msg = serde_json::to_string(&val).unwrap();
+                    let packet = sealer.lock().unwrap().seal(msg.as_bytes());
+
+                    // Anything bigger would be cut short on the way and then fail authentication,
+                    // so it is better not to send it at all
+                    if packet.len() > MAX_DATAGRAM {
+                        println!(
+                            "Dropped a {} message of {} bytes, which does not fit in a datagram",
+                            msg_type,
+                            packet.len()
+                        );
+                        if let Some(seq) = seq {
+                            outbox.lock().unwrap().pending.remove(&seq);
+                        }
+                        continue;
+                    }
+
+                    counters.lock().unwrap().bytes_sent += packet.len() as u64;
+
+                    link.send(packet).await;
+                }
+            }));
+        }
+
+        {
+            let tx = tx.clone();
+            let outbox = outbox.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(RESEND_INTERVAL).await;
+
+                    let pending = outbox
+                        .lock()
+                        .unwrap()
+                        .pending
+                        .values()
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    for msg in pending {
+                        if tx.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }));
+        }
+
+        {
+            let mut rx = broadcast_tx.subscribe();
+            let tx = tx.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(msg) => tx.send(msg).await.unwrap_or_else(print_err),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            println!("Dropped {} messages to a peer that fell behind", skipped)
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            }));
+        }
+
+        Ok(Self {
+            tx,
+            client_id,
+            name: client_data.name.clone(),
+            addr,
+            last_heard: Instant::now(),
+            established: false,
+            relayed: false,
+            started: Instant::now(),
+            handshake,
+            relay,
+            sealer,
+            opener,
+            outbox,
+            inbox: ReliableInbox::default(),
+            counters,
+            last_report: (Instant::now(), TrafficCounters::default()),
+            rtt: None,
+            tasks,
+        })
+    }
+
+    /// Smooths round trip times the same way TCP does, so one slow packet does not dominate.
+    fn record_rtt(&mut self, sent_at: f64) {
+        let sample = (timestamp() - sent_at).max(0.0);
+        self.rtt = Some(self.rtt.map_or(sample, |rtt| rtt * 0.875 + sample * 0.125));
+    }
+
+    /// Works out the traffic since the last report.
+    fn stats(&mut self) -> PeerStats {
+        let counters = *self.counters.lock().unwrap();
+        let (reported_at, last) = self.last_report;
+        let seconds = reported_at.elapsed().as_secs_f64().max(f64::EPSILON);
+
+        let expected = match (last.highest_received, counters.highest_received) {
+            (Some(last), Some(highest)) => highest - last,
+            (None, Some(highest)) => highest + 1,
+            _ => 0,
+        };
+        let received = counters.packets_received - last.packets_received;
+        let loss = if expected == 0 {
+            0.0
+        } else {
+            (1.0 - received as f64 / expected as f64).clamp(0.0, 1.0)
+        };
+
+        self.last_report = (Instant::now(), counters);
+
+        PeerStats {
+            client_id: self.client_id,
+            name: self.name.clone(),
+            relayed: self.relayed,
+            rtt: self.rtt,
+            sent: (counters.bytes_sent - last.bytes_sent) as f64 / seconds,
+            received: (counters.bytes_received - last.bytes_received) as f64 / seconds,
+            loss,
+            snapshot_depth: None,
+        }
+    }
+
+    fn seal(&self, msg: &Message) -> Result<Vec<u8>, NetworkError> {
+        let msg = serde_json::to_string(msg)?;
+
+        Ok(self.sealer.lock().unwrap().seal(msg.as_bytes()))
+    }
+
+    /// Decrypts a packet from this peer. Only packets that pass authentication count as having
+    /// heard from the peer.
+    fn open(&mut self, packet: &[u8]) -> Option<Message> {
+        let plaintext = if let Some(plaintext) = self.opener.open(packet) {
+            plaintext
+        } else {
+            println!(
+                "Rejected a packet from client {} that failed authentication",
+                self.client_id
+            );
+            return None;
+        };
+        self.last_heard = Instant::now();
+
+        {
+            let mut counters = self.counters.lock().unwrap();
+            counters.bytes_received += packet.len() as u64;
+            counters.packets_received += 1;
+            counters.highest_received = self.opener.highest();
+        }
+
+        let mut msg = match serde_json::from_slice::<Message>(&plaintext) {
+            Ok(msg) => msg,
+            Err(e) => {
+                println!(
+                    "Discarded a malformed packet from client {}: {}",
+                    self.client_id, e
+                );
+                return None;
+            }
+        };
+        msg.sender = Some(self.client_id);
+
+        Some(msg)
+    }
+
+    /// Sends everything for this peer through the rendezvous server from now on.
+    fn relay_through(&mut self, rendezvous_tx: mpsc::Sender<Message>) {
+        // The sending task only stops when the connection is dropped, so this cannot fail
+        let _ = self.relay.send(Some(rendezvous_tx));
+
+        // Nothing has been heard during the failed handshake, so the peer gets a fresh chance to
+        // be heard over the relay before it counts as timed out
+        if !self.established {
+            self.last_heard = Instant::now();
+        }
+        self.relayed = true;
+        self.establish();
+    }
+
+    fn establish(&mut self) -> bool {
+        self.handshake.abort();
+
+        let newly_established = !self.established;
+        self.established = true;
+
+        newly_established
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.established && self.last_heard.elapsed() > PEER_TIMEOUT
+    }
+
+    pub fn handshake_failed(&self) -> bool {
+        !self.established && self.started.elapsed() > HANDSHAKE_TIMEOUT
+    }
+}
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        self.handshake.abort();
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -139,224 +728,670 @@ pub struct RoomConnection {
     pub connection_type: RoomConnectionType,
 }
 
-pub struct CommunicationSockets {
-    tx: Arc<UdpSocket>,
-    rx: Arc<UdpSocket>,
-}
-
 pub struct TransmissionNetworkPortal {
     rendezvous_connection: Option<RendezvousConnector>,
     pub room_connection: Option<RoomConnection>,
-    sockets: Option<CommunicationSockets>,
+    socket: Option<Arc<UdpSocket>>,
+    game_tx: Option<mpsc::Sender<Message>>,
+    reconnect_token: Option<ReconnectToken>,
+    lost_peers: Vec<(u32, Instant)>,
+    status: ConnectionStatus,
+    block_direct: bool,
+    conditions: NetworkConditions,
+    identity: Identity,
+    name: String,
+    room_list_tx: Option<oneshot::Sender<Vec<RoomInfo>>>,
 }
 impl TransmissionNetworkPortal {
     pub fn new() -> Self {
         Self {
             rendezvous_connection: None,
             room_connection: None,
-            sockets: None,
+            socket: None,
+            game_tx: None,
+            reconnect_token: None,
+            lost_peers: vec![],
+            status: ConnectionStatus::default(),
+            block_direct: false,
+            conditions: NetworkConditions::default(),
+            identity: Identity::new(),
+            name: "Player".to_string(),
+            room_list_tx: None,
         }
     }
 
-    async fn handle_rendezvous_message(
-        this: Arc<Mutex<Self>>,
-        msg: Message,
-        broadcast_tx: broadcast::Sender<Message>,
-    ) -> Result<(), IoOrSerdeError> {
-        println!("Received rendezvous message: {:?}", msg);
-        let msg_type = msg.msg_type.as_str();
+    /// Ignores every datagram received directly from a peer, to try out the relay locally.
+    pub fn block_direct(mut self, block_direct: bool) -> Self {
+        self.block_direct = block_direct;
+        self
+    }
 
-        match msg_type {
-            "@response room/create" => {
-                let msg = serde_json::from_value::<CreateRoomResponse>(msg.data)?;
+    /// Simulates a bad network on everything sent to peers.
+    pub fn conditions(mut self, conditions: NetworkConditions) -> Self {
+        self.conditions = conditions;
+        self
+    }
 
-                let mut this = this.lock().await;
+    /// The name other players see for us.
+    pub fn name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
 
-                this.room_connection = Some(RoomConnection {
-                    room_id: msg.room_id,
-                    room_host: this.rendezvous_connection.as_mut().unwrap().client_id,
-                    connection_type: RoomConnectionType::Host(vec![]),
-                });
+    pub fn client_id(&self) -> Option<u32> {
+        self.rendezvous_connection.as_ref().map(|c| c.client_id)
+    }
 
-                println!("Room connection established");
+    async fn notify_game(&self, msg_type: &str, client_id: u32) {
+        if let Some(game_tx) = &self.game_tx {
+            game_tx
+                .send(Message::new(msg_type.to_string(), PeerEvent { client_id }))
+                .await
+                .unwrap_or_else(print_err);
+        }
+    }
+
+    async fn notify_peer_joined(&self, msg_type: &str, client_data: &ClientData) {
+        if let Some(game_tx) = &self.game_tx {
+            game_tx
+                .send(Message::new(
+                    msg_type.to_string(),
+                    PeerJoined {
+                        client_id: client_data.client_id,
+                        name: client_data.name.clone(),
+                    },
+                ))
+                .await
+                .unwrap_or_else(print_err);
+        }
+    }
+
+    async fn set_status(&mut self, status: ConnectionStatus) {
+        if self.status == status {
+            return;
+        }
+
+        self.status = status.clone();
+
+        if let Some(game_tx) = &self.game_tx {
+            game_tx
+                .send(Message::new("connection/status".to_string(), status))
+                .await
+                .unwrap_or_else(print_err);
+        }
+    }
+
+    /// Works out the status from the peers whose hole punching handshake has completed.
+    async fn refresh_status(&mut self) {
+        if self.room_connection.is_none() {
+            return;
+        }
+
+        let peers = self
+            .peers_mut()
+            .into_iter()
+            .filter(|peer| peer.established)
+            .map(|peer| peer.client_id)
+            .collect::<Vec<_>>();
+
+        if peers.is_empty() {
+            self.set_status(ConnectionStatus::WaitingForPeer).await;
+        } else {
+            self.set_status(ConnectionStatus::Connected(peers)).await;
+        }
+    }
+
+    async fn report_stats(&mut self) {
+        let peers = self
+            .peers_mut()
+            .into_iter()
+            .map(|peer| peer.stats())
+            .collect();
+
+        if let Some(game_tx) = &self.game_tx {
+            game_tx
+                .send(Message::new(
+                    "connection/stats".to_string(),
+                    ConnectionStats { peers },
+                ))
+                .await
+                .unwrap_or_else(print_err);
+        }
+    }
+
+    /// Passes an error on to the game, which keeps running without the connection that failed.
+    async fn report_error(&self, error: &NetworkError) {
+        if let Some(game_tx) = &self.game_tx {
+            game_tx
+                .send(Message::new(
+                    "connection/error".to_string(),
+                    ConnectionError {
+                        msg: error.to_string(),
+                    },
+                ))
+                .await
+                .unwrap_or_else(print_err);
+        } else {
+            println!("Network error: {}", error);
+        }
+    }
+
+    fn socket(&self) -> Result<&Arc<UdpSocket>, NetworkError> {
+        self.socket.as_ref().ok_or(NetworkError::NotConnected)
+    }
+
+    fn rendezvous_connection(&self) -> Result<&RendezvousConnector, NetworkError> {
+        self.rendezvous_connection
+            .as_ref()
+            .ok_or(NetworkError::NotConnected)
+    }
+
+    fn port(&self) -> Result<u16, NetworkError> {
+        Ok(self.socket()?.local_addr()?.port())
+    }
+
+    fn peers_mut(&mut self) -> Vec<&mut PeerConnection> {
+        match self
+            .room_connection
+            .as_mut()
+            .map(|c| &mut c.connection_type)
+        {
+            Some(RoomConnectionType::Host(peers)) => peers.iter_mut().collect(),
+            Some(RoomConnectionType::Client(peer)) => vec![peer],
+            None => vec![],
+        }
+    }
+
+    /// Looks up which peer a datagram came from and opens it with that peer's session key.
+    fn open_from_addr(&mut self, addr: SocketAddr, packet: &[u8]) -> Option<Message> {
+        self.peers_mut()
+            .into_iter()
+            .find(|peer| peer.addr == addr)?
+            .open(packet)
+    }
+
+    fn open_from_client(&mut self, client_id: u32, packet: &[u8]) -> Option<Message> {
+        self.peer_mut(client_id)?.open(packet)
+    }
+
+    /// Derives the keys for talking to a peer, bound to the room we met them in.
+    fn session(
+        &self,
+        client_data: &ClientData,
+        room_id: &str,
+    ) -> Result<(Sealer, Opener), NetworkError> {
+        let client_id = self.rendezvous_connection()?.client_id;
+
+        self.identity
+            .session(
+                &client_data.public_key,
+                room_id,
+                client_id,
+                client_data.client_id,
+            )
+            .ok_or(NetworkError::KeyExchange(client_data.client_id))
+    }
+
+    fn peer_mut(&mut self, client_id: u32) -> Option<&mut PeerConnection> {
+        self.peers_mut()
+            .into_iter()
+            .find(|peer| peer.client_id == client_id)
+    }
+
+    async fn acknowledge_punch(&mut self, client_id: u32) {
+        if let Some(peer) = self.peer_mut(client_id) {
+            peer.tx
+                .send(Message::new("connection/hole-punch-ack".to_string(), ()))
+                .await
+                .unwrap_or_else(print_err);
+        }
+    }
+
+    async fn punch_acknowledged(&mut self, client_id: u32) {
+        let (newly_established, name) = match self.peer_mut(client_id) {
+            Some(peer) => (peer.establish(), peer.name.clone()),
+            None => return,
+        };
+
+        if newly_established {
+            println!("Connection to {} (client {}) established", name, client_id);
+            self.refresh_status().await;
+        }
+    }
+
+    async fn disconnect_peer(&mut self, client_id: u32) {
+        self.lost_peers.retain(|(id, _)| *id != client_id);
+
+        let room_connection = if let Some(room_connection) = self.room_connection.as_mut() {
+            room_connection
+        } else {
+            return;
+        };
+
+        match &mut room_connection.connection_type {
+            RoomConnectionType::Host(peers) => peers.retain(|peer| peer.client_id != client_id),
+            RoomConnectionType::Client(_) if room_connection.room_host == client_id => {
+                println!("The host left the room");
+                self.room_connection = None;
+                self.reconnect_token = None;
+                self.notify_game("room/closed", client_id).await;
+                self.set_status(ConnectionStatus::Lost("The host left the room".to_string()))
+                    .await;
+                return;
             }
-            "@response room/join" => {
-                let msg = serde_json::from_value::<JoinRoomResponse>(msg.data)?;
+            RoomConnectionType::Client(_) => {}
+        }
 
-                if !msg.success {
-                    panic!("Failed to join room");
+        self.notify_game("peer/leave", client_id).await;
+        self.refresh_status().await;
+    }
+
+    async fn disconnect_timed_out_peers(&mut self) {
+        let failed = self
+            .peers_mut()
+            .into_iter()
+            .filter(|peer| peer.handshake_failed())
+            .map(|peer| peer.client_id)
+            .collect::<Vec<_>>();
+
+        for client_id in failed {
+            if let Some(rendezvous_tx) = self.rendezvous_connection().ok().map(|c| c.tx.clone()) {
+                if let Some(peer) = self.peer_mut(client_id) {
+                    println!(
+                        "Could not reach client {} directly, relaying through the rendezvous server",
+                        client_id
+                    );
+                    peer.relay_through(rendezvous_tx);
+                    self.refresh_status().await;
+                    continue;
                 }
+            }
 
-                let host_data = msg.host_data.unwrap();
+            self.report_error(&NetworkError::HandshakeFailed(client_id))
+                .await;
 
-                let ip: IpAddr = host_data.network_data.ip.parse().unwrap();
-                let send_port = host_data.network_data.send_port;
-                let recv_port = host_data.network_data.recv_port;
+            let room_connection = if let Some(room_connection) = self.room_connection.as_mut() {
+                room_connection
+            } else {
+                break;
+            };
 
-                let peer_send_addr = SocketAddr::new(ip, send_port);
-                let peer_recv_addr = SocketAddr::new(ip, recv_port);
+            match &mut room_connection.connection_type {
+                RoomConnectionType::Host(peers) => {
+                    peers.retain(|peer| peer.client_id != client_id);
+                    self.notify_game("peer/leave", client_id).await;
+                    self.refresh_status().await;
+                }
+                RoomConnectionType::Client(_) => {
+                    self.room_connection = None;
+                    self.set_status(ConnectionStatus::Lost(
+                        "Could not reach the host".to_string(),
+                    ))
+                    .await;
+                }
+            }
+        }
 
-                let mut this = this.lock().await;
+        let timed_out = self
+            .peers_mut()
+            .into_iter()
+            .filter(|peer| peer.timed_out())
+            .map(|peer| peer.client_id)
+            .collect::<Vec<_>>();
+
+        for client_id in timed_out {
+            let room_connection = if let Some(room_connection) = self.room_connection.as_mut() {
+                room_connection
+            } else {
+                break;
+            };
+
+            match &mut room_connection.connection_type {
+                RoomConnectionType::Host(peers) => {
+                    // The slot is held open for a while in case the client comes back
+                    println!("Client {} timed out", client_id);
+                    peers.retain(|peer| peer.client_id != client_id);
+                    self.lost_peers.push((client_id, Instant::now()));
+                    self.notify_game("peer/lost", client_id).await;
+                    self.refresh_status().await;
+                }
+                RoomConnectionType::Client(_) => {
+                    println!("Lost connection to the host, trying to rejoin");
+                    self.room_connection = None;
+                    self.notify_game("room/lost", client_id).await;
+                    self.set_status(ConnectionStatus::Lost(
+                        "Lost connection to the host".to_string(),
+                    ))
+                    .await;
+                    if let Err(e) = self.rejoin_room().await {
+                        self.report_error(&e).await;
+                    }
+                }
+            }
+        }
 
-                let sockets = this.sockets.as_ref().unwrap();
-                let send_socket = sockets.tx.clone();
-                let recv_socket = sockets.rx.clone();
+        let expired = self
+            .lost_peers
+            .iter()
+            .filter(|(_, lost_at)| lost_at.elapsed() > RECONNECT_GRACE)
+            .map(|(client_id, _)| *client_id)
+            .collect::<Vec<_>>();
 
-                let tx = {
-                    let msg = Message::new("connection/hole-punch".to_string(), ());
-                    let msg = serde_json::to_string(&msg)?;
+        for client_id in expired {
+            println!("Client {} did not come back", client_id);
+            self.disconnect_peer(client_id).await;
+        }
+    }
 
-                    recv_socket.send_to(msg.as_bytes(), peer_send_addr).await?;
+    async fn rejoin_room(&mut self) -> Result<(), NetworkError> {
+        let token = if let Some(token) = &self.reconnect_token {
+            token.clone()
+        } else {
+            return Ok(());
+        };
 
-                    {
-                        let recv_socket = recv_socket.clone();
-                        tokio::spawn(async move {
-                            loop {
-                                let msg = Message::new("connection/keep-alive".to_string(), ());
-                                let msg = serde_json::to_string(&msg).unwrap();
-                                recv_socket.send_to(msg.as_bytes(), peer_send_addr).await;
+        let port = self.port()?;
 
-                                tokio::time::sleep(Duration::from_secs(5)).await;
-                            }
-                        });
-                    }
+        // A fresh key pair means a fresh session key, so packet counters can start from zero again
+        self.identity = Identity::new();
 
-                    let socket = send_socket.clone();
+        let request = RejoinRoomRequest {
+            room_id: token.room_id,
+            client_id: token.client_id,
+            reconnect_token: token.token,
+            port,
+            public_key: self.identity.public_key(),
+            name: self.name.clone(),
+        };
 
-                    let (tx, mut rx) = mpsc::channel::<Message>(100);
+        self.rendezvous_connection()?
+            .tx
+            .send(Message::new("room/rejoin".to_string(), request))
+            .await
+            .unwrap_or_else(print_err);
 
-                    tokio::spawn(async move {
-                        while let Some(val) = rx.recv().await {
-                            let msg = serde_json::to_string(&val).unwrap();
+        Ok(())
+    }
 
-                            socket
-                                .send_to(msg.as_bytes(), peer_recv_addr)
-                                .await
-                                .unwrap_or_else(print_err);
-                        }
-                    });
+    /// Deals with the messages that only matter to the connection itself, handing back anything
+    /// that should be passed on to the game.
+    async fn receive_from_peer(this: &Arc<Mutex<Self>>, msg: Message) -> Option<Message> {
+        // Messages without a sender are reserved for events raised by the portal itself
+        let sender = msg.sender?;
 
-                    {
-                        let mut rx = broadcast_tx.subscribe();
-                        let tx = tx.clone();
-                        tokio::spawn(async move {
-                            while let Ok(msg) = rx.recv().await {
-                                tx.send(msg).await;
-                            }
-                        });
-                    }
+        match msg.msg_type.as_str() {
+            "connection/keep-alive" => {
+                let mut this = this.lock().await;
+                let peer = this.peer_mut(sender)?;
 
-                    tx
-                };
+                peer.tx
+                    .send(Message::new(
+                        "connection/keep-alive-ack".to_string(),
+                        msg.data,
+                    ))
+                    .await
+                    .unwrap_or_else(print_err);
+                None
+            }
+            "connection/keep-alive-ack" => {
+                let keep_alive = serde_json::from_value::<KeepAlive>(msg.data).ok()?;
 
-                {
-                    let recv = recv_socket.clone();
+                if let Some(peer) = this.lock().await.peer_mut(sender) {
+                    peer.record_rtt(keep_alive.sent_at);
+                }
+                None
+            }
+            "connection/hole-punch" => {
+                this.lock().await.acknowledge_punch(sender).await;
+                None
+            }
+            "connection/hole-punch-ack" => {
+                this.lock().await.punch_acknowledged(sender).await;
+                None
+            }
+            "connection/leave" => {
+                println!("Client {} left the room", sender);
+                this.lock().await.disconnect_peer(sender).await;
+                None
+            }
+            "reliable/message" => {
+                let reliable = serde_json::from_value::<ReliableMessage>(msg.data).ok()?;
 
-                    tokio::spawn(async move {
-                        let mut buf = [0u8; 4096];
-                        while let Ok((size, addr)) = recv.recv_from(&mut buf).await {
-                            let msg = String::from_utf8_lossy(&buf[..size]);
+                let mut this = this.lock().await;
+                let peer = this.peer_mut(sender)?;
+
+                // Acknowledged every time, as the last acknowledgement may have been lost
+                peer.tx
+                    .send(Message::new(
+                        "reliable/ack".to_string(),
+                        ReliableAck { seq: reliable.seq },
+                    ))
+                    .await
+                    .unwrap_or_else(print_err);
 
-                            buf = [0u8; 4096];
-                        }
-                    });
+                if !peer.inbox.deliver(reliable.seq) {
+                    return None;
+                }
+
+                let mut msg = reliable.message;
+                msg.sender = Some(sender);
+                Some(msg)
+            }
+            "reliable/ack" => {
+                let ack = serde_json::from_value::<ReliableAck>(msg.data).ok()?;
+
+                if let Some(peer) = this.lock().await.peer_mut(sender) {
+                    peer.outbox.lock().unwrap().pending.remove(&ack.seq);
+                }
+                None
+            }
+            _ => Some(msg),
+        }
+    }
+
+    async fn handle_rendezvous_message(
+        this: Arc<Mutex<Self>>,
+        msg: Message,
+        broadcast_tx: broadcast::Sender<Message>,
+    ) -> Result<(), NetworkError> {
+        let msg_type = msg.msg_type.as_str();
+
+        if msg_type == "@relay" {
+            let relayed = serde_json::from_value::<RelayMessage>(msg.data)?;
+
+            let packet = match base64::decode(&relayed.payload) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    println!("Discarded a malformed relayed packet: {}", e);
+                    return Ok(());
+                }
+            };
+
+            let msg = this
+                .lock()
+                .await
+                .open_from_client(relayed.client_id, &packet);
+            let msg = if let Some(msg) = msg {
+                msg
+            } else {
+                return Ok(());
+            };
+
+            if let Some(msg) = Self::receive_from_peer(&this, msg).await {
+                let game_tx = this.lock().await.game_tx.clone();
+                if let Some(game_tx) = game_tx {
+                    game_tx.send(msg).await.unwrap_or_else(print_err);
                 }
+            }
+
+            return Ok(());
+        }
+
+        println!("Received rendezvous message: {:?}", msg.redacted());
+
+        match msg_type {
+            "@response room/create" => {
+                let msg = serde_json::from_value::<CreateRoomResponse>(msg.data)?;
+
+                let mut this = this.lock().await;
 
                 this.room_connection = Some(RoomConnection {
                     room_id: msg.room_id,
-                    room_host: host_data.client_id,
-                    connection_type: RoomConnectionType::Client(PeerConnection {
-                        tx,
-                        client_id: host_data.client_id,
-                        peer_addr: SocketAddr::new(
-                            host_data.network_data.ip.parse().unwrap(),
-                            host_data.network_data.send_port,
-                        ),
-                    }),
+                    room_host: this.rendezvous_connection()?.client_id,
+                    connection_type: RoomConnectionType::Host(vec![]),
                 });
-            }
-            "@notification room/join" => {
-                let msg = serde_json::from_value::<JoinRoomNotification>(msg.data)?;
 
-                let ip: IpAddr = msg.network_data.ip.parse().unwrap();
-                let send_port = msg.network_data.send_port;
-                let recv_port = msg.network_data.recv_port;
+                println!("Room connection established");
+                this.refresh_status().await;
+            }
+            "@response room/list" => {
+                let msg = serde_json::from_value::<RoomListResponse>(msg.data)?;
 
-                let peer_send_addr = SocketAddr::new(ip, send_port);
-                let peer_recv_addr = SocketAddr::new(ip, recv_port);
+                if let Some(room_list_tx) = this.lock().await.room_list_tx.take() {
+                    let _ = room_list_tx.send(msg.rooms);
+                }
+            }
+            "@response room/join" | "@response room/rejoin" => {
+                let msg = serde_json::from_value::<RejoinRoomResponse>(msg.data)?;
+                let rejoining = msg_type == "@response room/rejoin";
 
                 let mut this = this.lock().await;
 
-                if let RoomConnectionType::Host(_) =
-                    &this.room_connection.as_ref().unwrap().connection_type
-                {
-                } else {
-                    panic!("Received a room/join notification while not a host")
+                if !msg.success && rejoining {
+                    let reason = msg.msg.unwrap_or_else(|| "unknown error".to_string());
+                    println!("Failed to rejoin room: {}", reason);
+
+                    if let Some(token) = this.reconnect_token.take() {
+                        this.notify_game("room/closed", token.room_host).await;
+                    }
+                    this.set_status(ConnectionStatus::Lost(reason)).await;
+                    return Ok(());
                 }
 
-                let sockets = this.sockets.as_ref().unwrap();
-                let send_socket = sockets.tx.clone();
-                let recv_socket = sockets.rx.clone();
+                if !msg.success {
+                    let reason = msg.msg.unwrap_or_else(|| "Failed to join room".to_string());
+                    this.set_status(ConnectionStatus::Lost(reason.clone()))
+                        .await;
 
-                let tx = {
-                    let client_id = msg.client_id;
+                    return Err(NetworkError::JoinRejected(reason));
+                }
 
-                    let msg = Message::new("connection/hole-punch".to_string(), ());
-                    let msg = serde_json::to_string(&msg)?;
+                let host_data = msg.host_data.ok_or(NetworkError::MissingHostData)?;
+                let client_id = this.rendezvous_connection()?.client_id;
 
-                    recv_socket.send_to(msg.as_bytes(), peer_send_addr).await?;
+                if let Some(token) = msg.reconnect_token {
+                    this.reconnect_token = Some(ReconnectToken {
+                        room_id: msg.room_id.clone(),
+                        room_host: host_data.client_id,
+                        client_id,
+                        token,
+                    });
+                }
 
-                    {
-                        let recv_socket = recv_socket.clone();
-                        tokio::spawn(async move {
-                            loop {
-                                let msg = Message::new("connection/keep-alive".to_string(), ());
-                                let msg = serde_json::to_string(&msg).unwrap();
-                                recv_socket.send_to(msg.as_bytes(), peer_send_addr).await;
+                let session = this.session(&host_data, &msg.room_id)?;
+                let peer = PeerConnection::connect(
+                    &host_data,
+                    session,
+                    this.socket()?,
+                    this.conditions,
+                    &broadcast_tx,
+                )
+                .await?;
 
-                                tokio::time::sleep(Duration::from_secs(5)).await;
-                            }
-                        });
-                    }
-                    let socket = send_socket.clone();
+                this.room_connection = Some(RoomConnection {
+                    room_id: msg.room_id,
+                    room_host: host_data.client_id,
+                    connection_type: RoomConnectionType::Client(peer),
+                });
 
-                    let (tx, mut rx) = mpsc::channel::<Message>(100);
+                if rejoining {
+                    println!("Rejoined {}'s room", host_data.name);
+                } else {
+                    println!("Joined {}'s room", host_data.name);
+                }
+                this.refresh_status().await;
+            }
+            "@notification room/join" => {
+                let msg = serde_json::from_value::<JoinRoomNotification>(msg.data)?;
 
-                    tokio::spawn(async move {
-                        while let Some(val) = rx.recv().await {
-                            let msg = serde_json::to_string(&val).unwrap();
+                let mut this = this.lock().await;
 
-                            socket
-                                .send_to(msg.as_bytes(), peer_recv_addr)
-                                .await
-                                .unwrap_or_else(print_err);
-                        }
-                    });
+                let room_id = match &this.room_connection {
+                    Some(RoomConnection {
+                        room_id,
+                        connection_type: RoomConnectionType::Host(_),
+                        ..
+                    }) => room_id.clone(),
+                    _ => return Err(NetworkError::UnexpectedMessage(msg_type.to_string())),
+                };
 
-                    {
-                        let mut rx = broadcast_tx.subscribe();
-                        let tx = tx.clone();
-                        tokio::spawn(async move {
-                            while let Ok(msg) = rx.recv().await {
-                                tx.send(msg).await;
-                            }
-                        });
+                let session = this.session(&msg, &room_id)?;
+                let peer = PeerConnection::connect(
+                    &msg,
+                    session,
+                    this.socket()?,
+                    this.conditions,
+                    &broadcast_tx,
+                )
+                .await?;
+
+                match this
+                    .room_connection
+                    .as_mut()
+                    .map(|c| &mut c.connection_type)
+                {
+                    Some(RoomConnectionType::Host(client_connections)) => {
+                        println!("{} (client {}) joined the room", msg.name, msg.client_id);
+                        client_connections.push(peer);
                     }
+                    _ => return Err(NetworkError::UnexpectedMessage(msg_type.to_string())),
+                }
 
-                    tx
-                };
+                this.notify_peer_joined("peer/join", &msg).await;
+            }
+            "@notification room/rejoin" => {
+                let msg = serde_json::from_value::<RejoinRoomNotification>(msg.data)?;
+
+                let mut this = this.lock().await;
 
-                match &mut this.room_connection.as_mut().unwrap().connection_type {
-                    RoomConnectionType::Host(client_connections) => {
-                        println!("Client {} joined the room", msg.client_id);
-                        client_connections.push(PeerConnection {
-                            tx,
-                            client_id: msg.client_id,
-                            peer_addr: SocketAddr::new(ip, msg.network_data.send_port),
-                        });
+                let room_id = this
+                    .room_connection
+                    .as_ref()
+                    .map(|c| c.room_id.clone())
+                    .ok_or_else(|| NetworkError::UnexpectedMessage(msg_type.to_string()))?;
+
+                let session = this.session(&msg, &room_id)?;
+                let peer = PeerConnection::connect(
+                    &msg,
+                    session,
+                    this.socket()?,
+                    this.conditions,
+                    &broadcast_tx,
+                )
+                .await?;
+
+                match this
+                    .room_connection
+                    .as_mut()
+                    .map(|c| &mut c.connection_type)
+                {
+                    Some(RoomConnectionType::Host(client_connections)) => {
+                        println!("{} (client {}) rejoined the room", msg.name, msg.client_id);
+                        client_connections.retain(|peer| peer.client_id != msg.client_id);
+                        client_connections.push(peer);
                     }
-                    _ => panic!("Received a room/join notification while not a host"),
+                    _ => return Err(NetworkError::UnexpectedMessage(msg_type.to_string())),
                 }
+
+                this.lost_peers.retain(|(id, _)| *id != msg.client_id);
+                this.notify_peer_joined("peer/rejoin", &msg).await;
+                this.refresh_status().await;
+            }
+            "@notification room/leave" => {
+                let msg = serde_json::from_value::<LeaveRoomNotification>(msg.data)?;
+
+                println!("Client {} left the room", msg.client_id);
+                this.lock().await.disconnect_peer(msg.client_id).await;
             }
             _ => println!("Received unknown message type {}", msg_type),
         }
@@ -368,106 +1403,135 @@ impl TransmissionNetworkPortal {
         mut self,
         addr: A,
         source: IpAddr,
-        send_port: u16,
-        recv_port: u16,
+        port: u16,
     ) -> Result<
         (
             Arc<Mutex<Self>>,
             (broadcast::Sender<Message>, mpsc::Receiver<Message>),
         ),
-        IoOrSerdeError,
+        NetworkError,
     >
     where
         A: ToSocketAddrs,
     {
-        let (tx, mut rx) = mpsc::channel::<Message>(100);
-
-        let stream = TcpStream::connect(addr).await?;
-        let (mut stream_rx, mut stream_tx) = stream.into_split();
-
-        let mut buf = [0u8; 4096];
-        let size = stream_rx.read(&mut buf).await?;
-
-        let response = serde_json::from_slice::<Message>(&buf[..size])?;
-        let response = serde_json::from_value::<ClientJoinedResponse>(response.data)?;
-
-        self.rendezvous_connection = Some(RendezvousConnector {
-            tx: tx.clone(),
-            client_id: response.client_id,
-        });
-
-        println!(
-            "Successfully registered to rendezvous server as {}",
-            response.client_id
-        );
+        let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "Could not resolve the rendezvous server",
+            )
+        })?;
 
-        let send_socket = UdpSocket::bind(SocketAddr::new(source, send_port)).await?;
-        let send_socket = Arc::new(send_socket);
+        let (stream_rx, rendezvous_connection) = Self::connect_rendezvous(addr).await?;
+        self.rendezvous_connection = Some(rendezvous_connection);
 
-        let recv_socket = UdpSocket::bind(SocketAddr::new(source, recv_port)).await?;
-        let recv_socket = Arc::new(recv_socket);
+        let socket = UdpSocket::bind(SocketAddr::new(source, port)).await?;
+        let socket = Arc::new(socket);
 
-        self.sockets = Some(CommunicationSockets {
-            tx: send_socket,
-            rx: recv_socket.clone(),
-        });
+        self.socket = Some(socket.clone());
 
         let this = Arc::new(Mutex::new(self));
 
-        tokio::spawn(async move {
-            while let Some(val) = rx.recv().await {
-                let value = serde_json::to_string(&val).unwrap();
-
-                println!("{}", value);
-
-                stream_tx
-                    .write_all(value.as_bytes())
-                    .await
-                    .unwrap_or_else(print_err);
-            }
-        });
+        {
+            let this = this.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(KEEP_ALIVE_INTERVAL).await;
+                    let mut this = this.lock().await;
+                    this.disconnect_timed_out_peers().await;
+                    this.report_stats().await;
+                }
+            });
+        }
 
-        let (tx, _) = broadcast::channel::<Message>(5);
+        let (tx, _) = broadcast::channel::<Message>(BROADCAST_CAPACITY);
 
         {
             let tx = tx.clone();
             let this = this.clone();
             tokio::spawn(async move {
-                let mut buf = [0u8; 4096];
-                while let Ok(size) = stream_rx.read(&mut buf).await {
-                    let msg = match serde_json::from_slice::<Message>(&buf[..size]) {
-                        Ok(msg) => msg,
-                        Err(e) => {
-                            println!(
-                                "Failed to parse json: {:?}, {}",
-                                String::from_utf8_lossy(buf.as_slice()),
-                                e
-                            );
-                            continue;
+                let mut stream_rx = stream_rx;
+
+                loop {
+                    while let Ok(Some(line)) = stream_rx.next_line().await {
+                        let msg = match serde_json::from_str::<Message>(&line) {
+                            Ok(msg) => msg,
+                            Err(e) => {
+                                println!("Failed to parse json: {:?}, {}", line, e);
+                                continue;
+                            }
+                        };
+
+                        if let Err(e) =
+                            Self::handle_rendezvous_message(this.clone(), msg, tx.clone()).await
+                        {
+                            this.lock().await.report_error(&e).await;
                         }
-                    };
+                    }
 
-                    Self::handle_rendezvous_message(this.clone(), msg, tx.clone())
-                        .await
-                        .unwrap_or_else(print_err);
+                    println!("Lost connection to the rendezvous server");
 
-                    buf = [0u8; 4096];
+                    stream_rx = loop {
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+
+                        match Self::connect_rendezvous(addr).await {
+                            Ok((stream_rx, rendezvous_connection)) => {
+                                this.lock()
+                                    .await
+                                    .rendezvous_reconnected(rendezvous_connection)
+                                    .await;
+                                break stream_rx;
+                            }
+                            Err(e) => {
+                                println!("Failed to reconnect to the rendezvous server: {}", e)
+                            }
+                        }
+                    };
                 }
             });
         }
 
         let rx = {
             let (tx, rx) = mpsc::channel(100);
-            let socket = recv_socket;
+            let this = this.clone();
+            let block_direct = this.lock().await.block_direct;
+
+            this.lock().await.game_tx = Some(tx.clone());
 
             tokio::spawn(async move {
-                let mut buf = [0; 4096];
-                while let Ok(size) = socket.recv(&mut buf).await {
-                    let msg = serde_json::from_slice::<Message>(&buf[0..size]).unwrap();
+                let mut buf = [0; MAX_DATAGRAM];
+                loop {
+                    let (size, addr) = match socket.recv_from(&mut buf).await {
+                        Ok(received) => received,
+                        Err(e) => {
+                            // Errors such as an unreachable peer only affect a single datagram
+                            println!("Failed to receive a datagram: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if block_direct {
+                        continue;
+                    }
+
+                    // Every peer shares the one socket, so datagrams are told apart by their source
+                    let msg = this.lock().await.open_from_addr(addr, &buf[0..size]);
+                    let msg = if let Some(msg) = msg {
+                        msg
+                    } else {
+                        continue;
+                    };
+
+                    let msg = if let Some(msg) = Self::receive_from_peer(&this, msg).await {
+                        msg
+                    } else {
+                        continue;
+                    };
 
-                    tx.send(msg).await;
+                    if tx.send(msg).await.is_err() {
+                        break;
+                    }
 
-                    buf = [0; 4096];
+                    buf = [0; MAX_DATAGRAM];
                 }
             });
 
@@ -477,51 +1541,435 @@ impl TransmissionNetworkPortal {
         Ok((this, (tx, rx)))
     }
 
-    pub async fn create_room(&mut self) -> Result<(), IoOrSerdeError> {
-        let tx = &self.rendezvous_connection.as_ref().unwrap().tx;
+    async fn connect_rendezvous(
+        addr: SocketAddr,
+    ) -> Result<(Lines<BufReader<OwnedReadHalf>>, RendezvousConnector), NetworkError> {
+        let (tx, mut rx) = mpsc::channel::<Message>(100);
 
-        let sockets = &self.sockets.as_ref().unwrap();
-        let send_port = sockets.tx.local_addr().unwrap().port();
-        let recv_port = sockets.rx.local_addr().unwrap().port();
+        let stream = TcpStream::connect(addr).await?;
+        let (stream_rx, mut stream_tx) = stream.into_split();
+
+        // Messages to and from the rendezvous server are separated by newlines
+        let mut stream_rx = BufReader::new(stream_rx).lines();
+        let response = stream_rx.next_line().await?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The rendezvous server closed the connection",
+            )
+        })?;
+
+        let response = serde_json::from_str::<Message>(&response)?;
+        let response = serde_json::from_value::<ClientJoinedResponse>(response.data)?;
+
+        println!(
+            "Successfully registered to rendezvous server as {}",
+            response.client_id
+        );
+
+        tokio::spawn(async move {
+            while let Some(val) = rx.recv().await {
+                let mut value = serde_json::to_string(&val).unwrap();
+
+                if val.msg_type != "relay/send" {
+                    println!("{}", serde_json::to_string(&val.redacted()).unwrap());
+                }
+
+                value.push('\n');
+                stream_tx
+                    .write_all(value.as_bytes())
+                    .await
+                    .unwrap_or_else(print_err);
+            }
+        });
+
+        Ok((
+            stream_rx,
+            RendezvousConnector {
+                tx,
+                client_id: response.client_id,
+            },
+        ))
+    }
+
+    /// The rendezvous server hands out a fresh id on every connection, so a client that was in a
+    /// room keeps its old id and asks to be let back into its slot.
+    async fn rendezvous_reconnected(&mut self, mut rendezvous_connection: RendezvousConnector) {
+        if let Some(token) = &self.reconnect_token {
+            rendezvous_connection.client_id = token.client_id;
+        }
+
+        for peer in self.peers_mut().into_iter().filter(|peer| peer.relayed) {
+            peer.relay_through(rendezvous_connection.tx.clone());
+        }
+
+        self.rendezvous_connection = Some(rendezvous_connection);
+        if let Err(e) = self.rejoin_room().await {
+            self.report_error(&e).await;
+        }
+    }
+
+    /// Private rooms are left out of the room list, but can still be joined by id.
+    pub async fn create_room(
+        &mut self,
+        max_clients: usize,
+        level: String,
+        public: bool,
+        password: Option<String>,
+    ) -> Result<(), NetworkError> {
+        let port = self.port()?;
 
         let data = serde_json::to_value(CreateRoomRequest {
-            max_clients: 2,
-            send_port,
-            recv_port,
+            max_clients,
+            port,
+            public_key: self.identity.public_key(),
+            name: self.name.clone(),
+            level,
+            public,
+            password,
         })?;
 
-        tx.send(
-            Message::new("room/create".to_string(), data)
-                .try_into()
-                .unwrap(),
-        )
-        .await
-        .unwrap_or_else(print_err);
+        self.rendezvous_connection()?
+            .tx
+            .send(Message::new("room/create".to_string(), data))
+            .await
+            .unwrap_or_else(print_err);
 
         Ok(())
     }
 
-    pub async fn join_room(&mut self, room_id: String) -> Result<(), IoOrSerdeError> {
-        let tx = &self.rendezvous_connection.as_ref().unwrap().tx;
-
-        let sockets = &self.sockets.as_ref().unwrap();
-        let send_port = sockets.tx.local_addr().unwrap().port();
-        let recv_port = sockets.rx.local_addr().unwrap().port();
+    pub async fn join_room(
+        &mut self,
+        room_id: String,
+        password: Option<String>,
+    ) -> Result<(), NetworkError> {
+        let port = self.port()?;
 
         let data = serde_json::to_value(JoinRoomRequest {
             room_id,
-            send_port,
-            recv_port,
+            port,
+            public_key: self.identity.public_key(),
+            name: self.name.clone(),
+            password,
         })?;
 
-        tx.send(
-            Message::new("room/join".to_string(), data)
-                .try_into()
-                .unwrap(),
-        )
-        .await
-        .unwrap_or_else(print_err);
+        self.rendezvous_connection()?
+            .tx
+            .send(Message::new("room/join".to_string(), data))
+            .await
+            .unwrap_or_else(print_err);
 
         Ok(())
     }
+
+    /// Asks the rendezvous server for the public rooms. The list arrives on the returned channel.
+    pub async fn list_rooms(&mut self) -> Result<oneshot::Receiver<Vec<RoomInfo>>, NetworkError> {
+        let (tx, rx) = oneshot::channel();
+        self.room_list_tx = Some(tx);
+
+        self.rendezvous_connection()?
+            .tx
+            .send(Message::new("room/list".to_string(), ()))
+            .await
+            .unwrap_or_else(print_err);
+
+        Ok(rx)
+    }
+
+    /// Tells the rendezvous server and every peer that we are leaving, so they do not have to wait
+    /// for the connection to time out.
+    pub async fn leave_room(&mut self) -> Result<(), NetworkError> {
+        let room_connection = if let Some(room_connection) = self.room_connection.take() {
+            room_connection
+        } else {
+            return Ok(());
+        };
+
+        let msg = Message::new("connection/leave".to_string(), ());
+        let peers = match &room_connection.connection_type {
+            RoomConnectionType::Host(peers) => peers.iter().collect(),
+            RoomConnectionType::Client(peer) => vec![peer],
+        };
+
+        if let Some(socket) = &self.socket {
+            for peer in peers {
+                socket.send_to(&peer.seal(&msg)?, peer.addr).await?;
+            }
+        }
+
+        if let Some(rendezvous_connection) = &self.rendezvous_connection {
+            rendezvous_connection
+                .tx
+                .send(Message::new(
+                    "room/leave".to_string(),
+                    LeaveRoomRequest {
+                        room_id: room_connection.room_id,
+                    },
+                ))
+                .await
+                .unwrap_or_else(print_err);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const ROOM_ID: &str = "ABCDEF";
+
+    fn client_data(client_id: u32, port: u16, public_key: String, name: String) -> ClientData {
+        ClientData {
+            client_id,
+            network_data: NetworkData {
+                ip: "127.0.0.1".to_string(),
+                port,
+            },
+            public_key,
+            name,
+        }
+    }
+
+    /// Just enough of the rendezvous server for one client to join another's room and for the two
+    /// to relay packets to each other.
+    async fn rendezvous_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let clients = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let host = Arc::new(std::sync::Mutex::new(None));
+
+        tokio::spawn(async move {
+            for client_id in 1.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (stream_rx, mut stream_tx) = stream.into_split();
+
+                let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+                clients.lock().unwrap().insert(client_id, tx.clone());
+                tokio::spawn(async move {
+                    while let Some(msg) = rx.recv().await {
+                        let mut line = serde_json::to_string(&msg).unwrap();
+                        line.push('\n');
+                        stream_tx.write_all(line.as_bytes()).await.unwrap();
+                    }
+                });
+
+                let send = {
+                    let clients = clients.clone();
+                    move |client_id: u32, msg_type: &str, data: Value| {
+                        if let Some(tx) = clients.lock().unwrap().get(&client_id) {
+                            let _ = tx.send(Message::new(msg_type.to_string(), data));
+                        }
+                    }
+                };
+                send(
+                    client_id,
+                    "@response connect",
+                    serde_json::to_value(ClientJoinedResponse { client_id }).unwrap(),
+                );
+
+                let host = host.clone();
+                tokio::spawn(async move {
+                    let mut lines = BufReader::new(stream_rx).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let msg = serde_json::from_str::<Message>(&line).unwrap();
+
+                        match msg.msg_type.as_str() {
+                            "room/create" => {
+                                let request =
+                                    serde_json::from_value::<CreateRoomRequest>(msg.data).unwrap();
+                                *host.lock().unwrap() = Some((client_id, request));
+
+                                let response = CreateRoomResponse {
+                                    room_id: ROOM_ID.to_string(),
+                                };
+                                send(
+                                    client_id,
+                                    "@response room/create",
+                                    serde_json::to_value(response).unwrap(),
+                                );
+                            }
+                            "room/join" => {
+                                let request =
+                                    serde_json::from_value::<JoinRoomRequest>(msg.data).unwrap();
+                                let (host_id, host_data) = {
+                                    let host = host.lock().unwrap();
+                                    let (host_id, room) = host.as_ref().unwrap();
+                                    (
+                                        *host_id,
+                                        client_data(
+                                            *host_id,
+                                            room.port,
+                                            room.public_key.clone(),
+                                            room.name.clone(),
+                                        ),
+                                    )
+                                };
+
+                                let response = JoinRoomResponse {
+                                    success: true,
+                                    room_id: ROOM_ID.to_string(),
+                                    msg: None,
+                                    host_data: Some(host_data),
+                                    reconnect_token: None,
+                                };
+                                send(
+                                    client_id,
+                                    "@response room/join",
+                                    serde_json::to_value(response).unwrap(),
+                                );
+
+                                let joined = client_data(
+                                    client_id,
+                                    request.port,
+                                    request.public_key,
+                                    request.name,
+                                );
+                                send(
+                                    host_id,
+                                    "@notification room/join",
+                                    serde_json::to_value(joined).unwrap(),
+                                );
+                            }
+                            "relay/send" => {
+                                let relayed =
+                                    serde_json::from_value::<RelayMessage>(msg.data).unwrap();
+                                let to = relayed.client_id;
+                                let from = RelayMessage {
+                                    client_id,
+                                    payload: relayed.payload,
+                                };
+                                send(to, "@relay", serde_json::to_value(from).unwrap());
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    /// Reads what the portal passes on to the game until a message matches, so the channel never
+    /// fills up and stalls the portal.
+    async fn wait_for(
+        rx: &mut mpsc::Receiver<Message>,
+        timeout: Duration,
+        matches: impl Fn(&Message) -> bool,
+    ) -> Option<Message> {
+        tokio::time::timeout(timeout, async {
+            while let Some(msg) = rx.recv().await {
+                if matches(&msg) {
+                    return Some(msg);
+                }
+            }
+            None
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    fn is_connected(msg: &Message) -> bool {
+        msg.msg_type == "connection/status"
+            && matches!(
+                serde_json::from_value(msg.data.clone()),
+                Ok(ConnectionStatus::Connected(_))
+            )
+    }
+
+    async fn relayed(portal: &Mutex<TransmissionNetworkPortal>) -> bool {
+        let mut portal = portal.lock().await;
+        let peers = portal.peers_mut();
+
+        !peers.is_empty() && peers.iter().all(|peer| peer.relayed)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocked_peers_fall_back_to_the_relay() {
+        let rendezvous = rendezvous_server().await;
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+
+        let (host, (_, mut host_rx)) = TransmissionNetworkPortal::new()
+            .block_direct(true)
+            .rendezvous_init(rendezvous, localhost, 0)
+            .await
+            .unwrap();
+        host.lock()
+            .await
+            .create_room(2, "Test".to_string(), true, None)
+            .await
+            .unwrap();
+        wait_for(&mut host_rx, Duration::from_secs(5), |msg| {
+            msg.msg_type == "connection/status"
+        })
+        .await
+        .expect("the room was never created");
+
+        let (client, (client_tx, mut client_rx)) = TransmissionNetworkPortal::new()
+            .block_direct(true)
+            .rendezvous_init(rendezvous, localhost, 0)
+            .await
+            .unwrap();
+        let client_id = client.lock().await.client_id().unwrap();
+        let joined_at = Instant::now();
+        client
+            .lock()
+            .await
+            .join_room(ROOM_ID.to_string(), None)
+            .await
+            .unwrap();
+
+        // Every datagram is dropped, so the handshake has to time out before either side relays
+        let deadline = HANDSHAKE_TIMEOUT + KEEP_ALIVE_INTERVAL * 3;
+        wait_for(&mut client_rx, deadline, is_connected)
+            .await
+            .expect("the client never connected");
+        wait_for(&mut host_rx, deadline, is_connected)
+            .await
+            .expect("the host never connected");
+        assert!(joined_at.elapsed() >= HANDSHAKE_TIMEOUT);
+        assert!(relayed(&client).await);
+        assert!(relayed(&host).await);
+
+        client_tx
+            .send(Message::new("test/relayed".to_string(), 42))
+            .unwrap();
+        let msg = wait_for(&mut host_rx, Duration::from_secs(5), |msg| {
+            msg.msg_type == "test/relayed"
+        })
+        .await
+        .expect("nothing made it through the relay");
+        assert_eq!(msg.sender, Some(client_id));
+        assert_eq!(msg.data, 42);
+    }
+
+    #[test]
+    fn passwords_are_hidden_from_the_log() {
+        let join = |password: Option<&str>| {
+            Message::new(
+                "room/join".to_string(),
+                JoinRoomRequest {
+                    room_id: ROOM_ID.to_string(),
+                    port: 0,
+                    public_key: String::new(),
+                    name: "Client".to_string(),
+                    password: password.map(str::to_string),
+                },
+            )
+        };
+
+        let printed = format!("{:?}", join(Some("hunter2")).redacted());
+        assert!(!printed.contains("hunter2"));
+        assert!(printed.contains("(hidden)"));
+
+        // Without a password there is nothing to hide, and it is not made to look like there was
+        assert!(join(None).redacted().data["password"].is_null());
+    }
 }
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{broadcast, mpsc};
+
+use super::systems::{ConnectionStatus, Message};
+#[cfg(test)]
+use super::systems::{PeerEvent, PeerJoined};
+
+/// How the `NetworkHandler` talks to its peers. Messages from peers carry their client id as the
+/// sender, and messages without a sender are events raised by the connection itself.
+pub trait Transport: Send {
+    /// Sends a message to every peer.
+    fn send(&self, msg: Message);
+
+    fn try_recv(&mut self) -> Option<Message>;
+}
+
+/// The channels to and from a `TransmissionNetworkPortal`.
+pub struct PortalTransport {
+    tx: broadcast::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+}
+impl PortalTransport {
+    pub fn new((tx, rx): (broadcast::Sender<Message>, mpsc::Receiver<Message>)) -> Self {
+        Self { tx, rx }
+    }
+}
+impl Transport for PortalTransport {
+    fn send(&self, msg: Message) {
+        // Sending only fails while no peer is subscribed, in which case there is nobody to tell
+        let _ = self.tx.send(msg);
+    }
+
+    fn try_recv(&mut self) -> Option<Message> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// A room whose peers all live in the same process, so several worlds can be run against each
+/// other without sockets or a rendezvous server. Like a real room, clients only talk to the host.
+/// Messages are delivered in the order they were sent, and nothing is ever lost.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    host_id: u32,
+    inboxes: Arc<Mutex<HashMap<u32, VecDeque<Message>>>>,
+}
+impl LoopbackNetwork {
+    /// Creates the room along with the host's end of it.
+    pub fn new(host_id: u32) -> (Self, LoopbackTransport) {
+        let network = Self {
+            host_id,
+            inboxes: Arc::new(Mutex::new(HashMap::new())),
+        };
+        network
+            .inboxes
+            .lock()
+            .unwrap()
+            .insert(host_id, VecDeque::new());
+        network.notify(
+            host_id,
+            "connection/status",
+            ConnectionStatus::WaitingForPeer,
+        );
+
+        let transport = LoopbackTransport {
+            client_id: host_id,
+            network: network.clone(),
+        };
+
+        (network, transport)
+    }
+
+    /// Adds a client to the room, raising the same events as a client joining through the portal.
+    #[cfg(test)]
+    pub fn join(&self, client_id: u32, name: &str) -> LoopbackTransport {
+        self.inboxes
+            .lock()
+            .unwrap()
+            .insert(client_id, VecDeque::new());
+
+        self.notify(
+            self.host_id,
+            "peer/join",
+            PeerJoined {
+                client_id,
+                name: name.to_string(),
+            },
+        );
+        self.notify(
+            client_id,
+            "connection/status",
+            ConnectionStatus::Connected(vec![self.host_id]),
+        );
+        self.notify(
+            self.host_id,
+            "connection/status",
+            ConnectionStatus::Connected(self.clients()),
+        );
+
+        LoopbackTransport {
+            client_id,
+            network: self.clone(),
+        }
+    }
+
+    /// Takes a client out of the room. Anything still waiting to be delivered to it is dropped.
+    #[cfg(test)]
+    pub fn leave(&self, client_id: u32) {
+        self.inboxes.lock().unwrap().remove(&client_id);
+
+        self.notify(self.host_id, "peer/leave", PeerEvent { client_id });
+
+        let clients = self.clients();
+        let status = if clients.is_empty() {
+            ConnectionStatus::WaitingForPeer
+        } else {
+            ConnectionStatus::Connected(clients)
+        };
+        self.notify(self.host_id, "connection/status", status);
+    }
+
+    /// Raises a connection event on one peer, as its portal would.
+    pub fn notify(&self, client_id: u32, msg_type: &str, data: impl serde::Serialize) {
+        if let Some(inbox) = self.inboxes.lock().unwrap().get_mut(&client_id) {
+            inbox.push_back(Message::new(msg_type.to_string(), data));
+        }
+    }
+
+    #[cfg(test)]
+    fn clients(&self) -> Vec<u32> {
+        let mut clients = self
+            .inboxes
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .filter(|id| *id != self.host_id)
+            .collect::<Vec<_>>();
+        clients.sort_unstable();
+        clients
+    }
+}
+
+/// One peer's end of a `LoopbackNetwork`.
+pub struct LoopbackTransport {
+    client_id: u32,
+    network: LoopbackNetwork,
+}
+impl Transport for LoopbackTransport {
+    fn send(&self, msg: Message) {
+        let mut inboxes = self.network.inboxes.lock().unwrap();
+        let is_host = self.client_id == self.network.host_id;
+
+        for (&client_id, inbox) in inboxes.iter_mut() {
+            if client_id == self.client_id || (!is_host && client_id != self.network.host_id) {
+                continue;
+            }
+
+            let mut msg = msg.clone();
+            msg.sender = Some(self.client_id);
+            inbox.push_back(msg);
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<Message> {
+        self.network
+            .inboxes
+            .lock()
+            .unwrap()
+            .get_mut(&self.client_id)?
+            .pop_front()
+    }
+}
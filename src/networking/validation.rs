@@ -0,0 +1,258 @@
+use std::fmt::{Display, Formatter};
+
+use specs::{Component, VecStorage};
+
+use crate::components::Collider;
+use crate::sat::intersection;
+use crate::systems::{ACCELERATION_DUE_TO_GRAVITY, JUMP_VELOCITY, PLAYER_SPEED};
+use crate::util::Vec2;
+
+use super::interpolation::Snapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    Speed(f32),
+    JumpVelocity(f32),
+    Acceleration(Vec2),
+    Teleport(f32),
+    Clock(f64),
+    Collision(f32),
+}
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Speed(v) => write!(f, "horizontal speed {} exceeds the limit", v),
+            Self::JumpVelocity(v) => write!(f, "vertical velocity {} exceeds the limit", v),
+            Self::Acceleration(a) => write!(f, "acceleration ({}, {}) is impossible", a.x, a.y),
+            Self::Teleport(d) => write!(f, "moved {} units further than possible", d),
+            Self::Clock(dt) => write!(f, "timestamp advanced {}s faster than real time", dt),
+            Self::Collision(d) => write!(f, "penetrated a floor by {} units", d),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MovementLimits {
+    pub max_speed: f32,
+    pub jump_velocity: f32,
+    pub gravity: f32,
+    pub tolerance: f32,
+    pub clock_slack: f64,
+}
+impl Default for MovementLimits {
+    fn default() -> Self {
+        Self {
+            max_speed: PLAYER_SPEED,
+            jump_velocity: JUMP_VELOCITY,
+            gravity: ACCELERATION_DUE_TO_GRAVITY,
+            tolerance: 0.1,
+            clock_slack: 0.25,
+        }
+    }
+}
+impl MovementLimits {
+    pub fn check_state(&self, snapshot: &Snapshot) -> Result<(), Violation> {
+        let velocity = snapshot.velocity;
+        let acceleration = snapshot.acceleration;
+
+        if velocity.x.abs() > self.max_speed + self.tolerance {
+            return Err(Violation::Speed(velocity.x));
+        }
+
+        if velocity.y > self.jump_velocity + self.tolerance {
+            return Err(Violation::JumpVelocity(velocity.y));
+        }
+
+        if acceleration.x.abs() > self.tolerance
+            || acceleration.y < self.gravity - self.tolerance
+            || acceleration.y > self.tolerance
+        {
+            return Err(Violation::Acceleration(acceleration));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `next` is reachable from `previous` given the time that has really passed
+    /// locally, so a sender cannot gain extra movement by running its clock fast.
+    pub fn check_move(
+        &self,
+        previous: &Snapshot,
+        previous_received_at: f64,
+        next: &Snapshot,
+        received_at: f64,
+    ) -> Result<(), Violation> {
+        let dt = next.timestamp - previous.timestamp;
+        let local_dt = received_at - previous_received_at;
+
+        if dt > local_dt + self.clock_slack {
+            return Err(Violation::Clock(dt - local_dt));
+        }
+
+        let dt = dt.max(0.0) as f32;
+        let displacement = next.position - previous.position;
+
+        let max_dx = self.max_speed * dt + self.tolerance;
+        if displacement.x.abs() > max_dx {
+            return Err(Violation::Teleport(displacement.x.abs() - max_dx));
+        }
+
+        let max_dy = self.jump_velocity * dt + self.tolerance;
+        if displacement.y > max_dy {
+            return Err(Violation::Teleport(displacement.y - max_dy));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_collision(
+        &self,
+        collider: &Collider<'static>,
+        position: Vec2,
+        floors: &[(Vec2, &Collider<'static>)],
+    ) -> Result<(), Violation> {
+        for (floor_pos, floor) in floors {
+            if let Some(n) = intersection(collider.shape(), position, floor.shape(), *floor_pos) {
+                if n.magnitude() > self.tolerance {
+                    return Err(Violation::Collision(n.magnitude()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Remembers the last accepted update for an entity whose state is sent by a client, so that each
+/// new update can be checked against what was physically possible since then.
+#[derive(Debug, Default)]
+pub struct MovementValidator {
+    last_accepted: Option<(Snapshot, f64)>,
+    pub violations: usize,
+}
+impl MovementValidator {
+    pub fn validate(
+        &mut self,
+        limits: &MovementLimits,
+        snapshot: &Snapshot,
+        received_at: f64,
+        collider: Option<&Collider<'static>>,
+        floors: &[(Vec2, &Collider<'static>)],
+    ) -> Result<(), Violation> {
+        let result = limits
+            .check_state(snapshot)
+            .and_then(|_| match &self.last_accepted {
+                Some((previous, previous_received_at)) => {
+                    limits.check_move(previous, *previous_received_at, snapshot, received_at)
+                }
+                None => Ok(()),
+            })
+            .and_then(|_| match collider {
+                Some(collider) => limits.check_collision(collider, snapshot.position, floors),
+                None => Ok(()),
+            });
+
+        match result {
+            Ok(()) => self.last_accepted = Some((*snapshot, received_at)),
+            Err(_) => self.violations += 1,
+        }
+
+        result
+    }
+}
+impl Component for MovementValidator {
+    type Storage = VecStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moving_at(timestamp: f64, x: f32, vx: f32) -> Snapshot {
+        Snapshot::new(
+            timestamp,
+            Vec2::new(x, 0.0),
+            Vec2::new(vx, 0.0),
+            Vec2::new(0.0, ACCELERATION_DUE_TO_GRAVITY),
+        )
+    }
+
+    #[test]
+    fn a_legal_move_is_accepted() {
+        let limits = MovementLimits::default();
+        let mut validator = MovementValidator::default();
+
+        assert!(validator
+            .validate(&limits, &moving_at(0.0, 0.0, PLAYER_SPEED), 0.0, None, &[])
+            .is_ok());
+        assert!(validator
+            .validate(
+                &limits,
+                &moving_at(1.0, PLAYER_SPEED, PLAYER_SPEED),
+                1.0,
+                None,
+                &[]
+            )
+            .is_ok());
+        assert_eq!(validator.violations, 0);
+    }
+
+    #[test]
+    fn an_impossible_move_is_rejected() {
+        let limits = MovementLimits::default();
+        let mut validator = MovementValidator::default();
+
+        validator
+            .validate(&limits, &moving_at(0.0, 0.0, 0.0), 0.0, None, &[])
+            .unwrap();
+
+        let teleport = moving_at(0.1, PLAYER_SPEED * 10.0, 0.0);
+        assert!(matches!(
+            validator.validate(&limits, &teleport, 0.1, None, &[]),
+            Err(Violation::Teleport(_))
+        ));
+
+        let too_fast = moving_at(0.2, 0.0, PLAYER_SPEED * 2.0);
+        assert!(matches!(
+            validator.validate(&limits, &too_fast, 0.2, None, &[]),
+            Err(Violation::Speed(_))
+        ));
+        assert_eq!(validator.violations, 2);
+    }
+
+    #[test]
+    fn moves_are_checked_against_the_last_accepted_state() {
+        let limits = MovementLimits::default();
+        let mut validator = MovementValidator::default();
+
+        validator
+            .validate(&limits, &moving_at(0.0, 0.0, 0.0), 0.0, None, &[])
+            .unwrap();
+        validator
+            .validate(&limits, &moving_at(0.1, 100.0, 0.0), 0.1, None, &[])
+            .unwrap_err();
+
+        // Had the teleport been accepted, this would be a legal step on from it
+        let after_teleport = moving_at(0.2, 100.0 + PLAYER_SPEED * 0.1, PLAYER_SPEED);
+        assert!(validator
+            .validate(&limits, &after_teleport, 0.2, None, &[])
+            .is_err());
+        assert!(validator
+            .validate(&limits, &moving_at(0.2, 0.0, 0.0), 0.2, None, &[])
+            .is_ok());
+    }
+
+    #[test]
+    fn a_fast_clock_is_rejected() {
+        let limits = MovementLimits::default();
+        let mut validator = MovementValidator::default();
+
+        validator
+            .validate(&limits, &moving_at(0.0, 0.0, 0.0), 0.0, None, &[])
+            .unwrap();
+        assert!(matches!(
+            validator.validate(&limits, &moving_at(2.0, 0.0, 0.0), 0.5, None, &[]),
+            Err(Violation::Clock(_))
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+use sdl2::pixels::Color;
+use serde_derive::{Deserialize, Serialize};
+use specs::Builder;
+
+use crate::components::{
+    Acceleration, Collider, FloorCollider, FloorCollision, Grounded, PlayerInput, Position,
+    RenderDescriptor, Velocity,
+};
+use crate::networking::components::Vec2ForSerde;
+use crate::systems::ACCELERATION_DUE_TO_GRAVITY;
+use crate::util::{Rect, Vec2};
+
+pub const PLAYER_WIDTH: f32 = 1.0;
+pub const PLAYER_HEIGHT: f32 = 1.0;
+
+pub const LOCAL_PLAYER_COLOUR: Color = Color::RGB(255, 0, 0);
+pub const REMOTE_PLAYER_COLOUR: Color = Color::RGB(0, 0, 255);
+pub const BOX_COLOUR: Color = Color::RGB(150, 100, 50);
+
+pub fn player<B: Builder>(builder: B, position: Vec2, colour: Color) -> B {
+    builder
+        .with(Position(position))
+        .with(Velocity(Vec2::new(0.0, 0.0)))
+        .with(Acceleration(Vec2::new(0.0, ACCELERATION_DUE_TO_GRAVITY)))
+        .with(RenderDescriptor::new(
+            Rect::from_centre(Vec2::new(0.0, 0.0), PLAYER_WIDTH, PLAYER_HEIGHT),
+            colour,
+        ))
+        .with(Collider::new(Rect::from_centre(
+            Vec2::new(0.0, 0.0),
+            PLAYER_WIDTH,
+            PLAYER_HEIGHT,
+        )))
+        .with(FloorCollision {})
+}
+
+pub fn controlled_player<B: Builder>(builder: B, position: Vec2, colour: Color) -> B {
+    player(builder, position, colour)
+        .with(Grounded(true))
+        .with(PlayerInput::default())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct RectForSerde {
+    pub top_left: Vec2ForSerde,
+    pub bottom_right: Vec2ForSerde,
+}
+impl From<Rect> for RectForSerde {
+    fn from(rect: Rect) -> Self {
+        Self {
+            top_left: rect.top_left().into(),
+            bottom_right: rect.bottom_right().into(),
+        }
+    }
+}
+impl Into<Rect> for RectForSerde {
+    fn into(self) -> Rect {
+        Rect::new(self.top_left.into(), self.bottom_right.into())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum PrefabComponent {
+    Position(Vec2ForSerde),
+    Velocity(Vec2ForSerde),
+    Acceleration(Vec2ForSerde),
+    Grounded(bool),
+    RenderDescriptor {
+        rectangle: RectForSerde,
+        colour: (u8, u8, u8),
+    },
+    Collider(RectForSerde),
+    FloorCollider,
+    FloorCollision,
+}
+
+/// A serialisable description of an entity, used to create the same entity on every peer.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Prefab {
+    pub components: Vec<PrefabComponent>,
+}
+impl Prefab {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, component: PrefabComponent) -> Self {
+        self.components.push(component);
+        self
+    }
+
+    pub fn build<B: Builder>(&self, builder: B) -> B {
+        self.components
+            .iter()
+            .fold(builder, |builder, component| match *component {
+                PrefabComponent::Position(v) => builder.with(Position(v.into())),
+                PrefabComponent::Velocity(v) => builder.with(Velocity(v.into())),
+                PrefabComponent::Acceleration(v) => builder.with(Acceleration(v.into())),
+                PrefabComponent::Grounded(g) => builder.with(Grounded(g)),
+                PrefabComponent::RenderDescriptor {
+                    rectangle,
+                    colour: (r, g, b),
+                } => builder.with(RenderDescriptor::new(rectangle.into(), Color::RGB(r, g, b))),
+                PrefabComponent::Collider(rectangle) => {
+                    let rectangle: Rect = rectangle.into();
+                    builder.with(Collider::new(rectangle))
+                }
+                PrefabComponent::FloorCollider => builder.with(FloorCollider {}),
+                PrefabComponent::FloorCollision => builder.with(FloorCollision {}),
+            })
+    }
+}
+
+pub fn crate_box(position: Vec2, size: f32) -> Prefab {
+    let rectangle = Rect::from_centre(Vec2::new(0.0, 0.0), size, size);
+
+    Prefab::new()
+        .with(PrefabComponent::Position(position.into()))
+        .with(PrefabComponent::Velocity(Vec2::new(0.0, 0.0).into()))
+        .with(PrefabComponent::Acceleration(
+            Vec2::new(0.0, ACCELERATION_DUE_TO_GRAVITY).into(),
+        ))
+        .with(PrefabComponent::RenderDescriptor {
+            rectangle: rectangle.into(),
+            colour: (BOX_COLOUR.r, BOX_COLOUR.g, BOX_COLOUR.b),
+        })
+        .with(PrefabComponent::Collider(rectangle.into()))
+        .with(PrefabComponent::FloorCollision)
+}
@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use serde_derive::{Deserialize, Serialize};
+use specs::{Dispatcher, Join, RunNow, World, WorldExt};
+use tokio::time;
+
+use crate::components::{FloorCollision, Grounded, Position, Velocity};
+use crate::game::{self, TICK_RATE};
+use crate::networking::transport::LoopbackNetwork;
+use crate::resources::{GameState, Playback, SystemState};
+use crate::systems::{EventSystem, RenderSystem};
+use crate::{Args, NetworkMode};
+
+/// Bumped whenever the format changes, as old recordings will no longer play back.
+const VERSION: u32 = 1;
+/// Ticks between the snapshots used to check that a recording still plays out as it did.
+const SNAPSHOT_INTERVAL: u64 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct EntityRecord {
+    id: u32,
+    position: (f32, f32),
+    velocity: (f32, f32),
+    grounded: Option<bool>,
+}
+
+/// One line of a recording.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Header {
+        version: u32,
+        tick_rate: u32,
+    },
+    Tick {
+        tick: u64,
+        keys: Vec<String>,
+    },
+    Snapshot {
+        tick: u64,
+        entities: Vec<EntityRecord>,
+    },
+}
+
+/// Everything that moves, which is enough to tell when a replay has gone its own way.
+fn snapshot(world: &World) -> Vec<EntityRecord> {
+    let entities = world.entities();
+    let position = world.read_storage::<Position>();
+    let velocity = world.read_storage::<Velocity>();
+    let grounded = world.read_storage::<Grounded>();
+    let floor_collision = world.read_storage::<FloorCollision>();
+
+    (
+        &entities,
+        &position,
+        &velocity,
+        grounded.maybe(),
+        &floor_collision,
+    )
+        .join()
+        .map(|(entity, position, velocity, grounded, _)| EntityRecord {
+            id: entity.id(),
+            position: (position.0.x, position.0.y),
+            velocity: (velocity.0.x, velocity.0.y),
+            grounded: grounded.map(|grounded| grounded.0),
+        })
+        .collect()
+}
+
+/// Writes the keys held on each tick to a file, one JSON record per line.
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+
+        let mut recorder = Self {
+            file: BufWriter::new(file),
+        };
+        recorder.write(&Record::Header {
+            version: VERSION,
+            tick_rate: TICK_RATE,
+        })?;
+        Ok(recorder)
+    }
+
+    /// Records the tick about to run, so must be called before the systems are dispatched.
+    pub fn record(&mut self, world: &World) -> Result<(), String> {
+        let tick = world.read_resource::<GameState>().tick;
+        let mut keys = world
+            .read_resource::<GameState>()
+            .keys_held
+            .iter()
+            .map(|key| key.name())
+            .collect::<Vec<_>>();
+        keys.sort();
+
+        self.write(&Record::Tick { tick, keys })?;
+
+        if tick.is_multiple_of(SNAPSHOT_INTERVAL) {
+            self.write(&Record::Snapshot {
+                tick,
+                entities: snapshot(world),
+            })?;
+            self.file.flush().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, record: &Record) -> Result<(), String> {
+        serde_json::to_writer(&mut self.file, record).map_err(|e| e.to_string())?;
+        writeln!(self.file).map_err(|e| e.to_string())
+    }
+}
+
+/// A recording loaded back in, ready to play.
+struct Replay {
+    keys: Vec<HashSet<Keycode>>,
+    snapshots: BTreeMap<u64, Vec<EntityRecord>>,
+}
+impl Replay {
+    fn load(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+
+        let mut replay = Self {
+            keys: Vec::new(),
+            snapshots: BTreeMap::new(),
+        };
+
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let record = serde_json::from_str::<Record>(&line)
+                .map_err(|e| format!("Line {} of the replay is invalid: {}", number + 1, e))?;
+
+            match record {
+                Record::Header { version, tick_rate } => {
+                    if version != VERSION || tick_rate != TICK_RATE {
+                        return Err(format!(
+                            "The replay was recorded by an incompatible version ({} at {} ticks/s)",
+                            version, tick_rate
+                        ));
+                    }
+                }
+                Record::Tick { tick, keys } => {
+                    if tick != replay.len() {
+                        return Err(format!("The replay is missing tick {}", replay.len()));
+                    }
+                    let keys = keys
+                        .iter()
+                        .map(|name| {
+                            Keycode::from_name(name)
+                                .ok_or_else(|| format!("Unknown key {:?} on tick {}", name, tick))
+                        })
+                        .collect::<Result<_, _>>()?;
+                    replay.keys.push(keys);
+                }
+                Record::Snapshot { tick, entities } => {
+                    replay.snapshots.insert(tick, entities);
+                }
+            }
+        }
+
+        Ok(replay)
+    }
+
+    fn len(&self) -> u64 {
+        self.keys.len() as u64
+    }
+
+    /// Runs the next tick with the keys that were held when it was recorded, checking the world
+    /// against the snapshot taken at the same point.
+    fn play_tick(&self, world: &mut World, dispatcher: &mut Dispatcher) -> Result<(), String> {
+        let tick = world.read_resource::<GameState>().tick;
+        let keys = self.keys.get(tick as usize).cloned().unwrap_or_default();
+        {
+            let mut game_state = world.write_resource::<GameState>();
+            game_state.keys_pressed = &keys - &game_state.keys_held;
+            game_state.keys_released = &game_state.keys_held - &keys;
+            game_state.keys_held = keys;
+        }
+
+        game::start_tick(world);
+        let verified = self.verify(tick, world);
+        dispatcher.dispatch(world);
+        game::end_tick(world);
+
+        verified
+    }
+
+    fn verify(&self, tick: u64, world: &World) -> Result<(), String> {
+        let expected = match self.snapshots.get(&tick) {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let actual = snapshot(world);
+
+        for recorded in expected {
+            match actual.iter().find(|entity| entity.id == recorded.id) {
+                Some(entity) if entity != recorded => {
+                    return Err(format!(
+                        "Tick {}: entity {} is {:?} but was recorded as {:?}",
+                        tick, recorded.id, entity, recorded
+                    ))
+                }
+                Some(_) => {}
+                None => {
+                    return Err(format!(
+                        "Tick {}: entity {} was recorded but does not exist",
+                        tick, recorded.id
+                    ))
+                }
+            }
+        }
+        if actual.len() != expected.len() {
+            return Err(format!(
+                "Tick {}: there are {} entities but {} were recorded",
+                tick,
+                actual.len(),
+                expected.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A fresh world with the level loaded, ready to play from the first tick. Nobody else is ever in
+/// the game, so the network handler is given a room of its own.
+fn new_session<'a, 'b>(args: &Args) -> Result<(World, Dispatcher<'a, 'b>), String> {
+    let mut world = game::create_world(args, NetworkMode::None)?;
+    let (_, transport) = LoopbackNetwork::new(0);
+    let mut dispatcher = game::simulation(args, transport, 0);
+    dispatcher.setup(&mut world);
+    game::load_level(&mut world, NetworkMode::None, 0, &args.name);
+
+    Ok((world, dispatcher))
+}
+
+/// Plays the whole recording as fast as possible, failing at the first snapshot that differs.
+pub fn verify_replay(args: &Args, path: &Path) -> Result<(), String> {
+    let replay = Replay::load(path)?;
+    let (mut world, mut dispatcher) = new_session(args)?;
+
+    for _ in 0..replay.len() {
+        replay
+            .play_tick(&mut world, &mut dispatcher)
+            .map_err(|e| format!("The replay diverged. {}", e))?;
+    }
+
+    println!(
+        "Played {} ticks, matching all {} snapshots",
+        replay.len(),
+        replay.snapshots.len()
+    );
+    Ok(())
+}
+
+pub async fn replay_main(args: Args, path: &Path) -> Result<(), String> {
+    if args.headless {
+        return verify_replay(&args, path);
+    }
+
+    let replay = Replay::load(path)?;
+
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+
+    let window = video_subsystem
+        .window("Team Platformer - Replay", 800, 600)
+        .position_centered()
+        .vulkan()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
+    canvas.present();
+
+    let mut events = EventSystem::new(sdl_context.event_pump()?, video_subsystem.text_input());
+    let mut render = RenderSystem::new(canvas);
+
+    let (mut world, mut dispatcher) = new_session(&args)?;
+    events.setup(&mut world);
+    render.setup(&mut world);
+
+    world.insert(Playback {
+        active: true,
+        length: replay.len(),
+        ..Playback::default()
+    });
+
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / TICK_RATE as f64));
+
+    loop {
+        interval.tick().await;
+
+        events.run_now(&world);
+        if let SystemState::Quit = world.read_resource::<GameState>().system_state {
+            break;
+        }
+
+        let (tick, target) = {
+            let mut playback = world.write_resource::<Playback>();
+            let target = match playback.seek_to.take() {
+                Some(target) => target.min(replay.len()),
+                None if playback.paused && !playback.step => playback.tick,
+                None => (playback.tick + 1).min(replay.len()),
+            };
+            playback.step = false;
+            (playback.tick, target)
+        };
+
+        // There is no going back, so seeking backwards starts again from the beginning
+        if target < tick {
+            let playback = world.remove::<Playback>().unwrap_or_default();
+            (world, dispatcher) = new_session(&args)?;
+            events.setup(&mut world);
+            render.setup(&mut world);
+            world.insert(Playback {
+                tick: 0,
+                diverged_at: None,
+                ..playback
+            });
+        }
+
+        while world.read_resource::<Playback>().tick < target {
+            let played = replay.play_tick(&mut world, &mut dispatcher);
+
+            let mut playback = world.write_resource::<Playback>();
+            if let Err(e) = played {
+                if playback.diverged_at.is_none() {
+                    println!("The replay diverged. {}", e);
+                    playback.diverged_at = Some(playback.tick);
+                }
+            }
+            playback.tick += 1;
+        }
+
+        {
+            let mut playback = world.write_resource::<Playback>();
+            if playback.tick == playback.length {
+                playback.paused = true;
+            }
+        }
+        render.run_now(&world);
+    }
+
+    Ok(())
+}
@@ -1,12 +1,16 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
 
 use sdl2::keyboard::Keycode;
 
+use crate::components::Emote;
+use crate::networking::systems::PeerStats;
 use sdl2::rect::Rect as SDLRect;
 use crate::util::{Rect, Vec2};
 
 #[derive(Clone, Copy, Debug)]
 pub enum SystemState {
+    Lobby,
     Running,
     Quit,
 }
@@ -23,6 +27,8 @@ pub struct GameState {
     pub keys_released: HashSet<Keycode>,
     pub keys_held: HashSet<Keycode>,
     pub delta_t: f32,
+    pub elapsed: f64,
+    pub tick: u64,
 }
 impl GameState {
     pub fn new(system_state: SystemState) -> Self {
@@ -32,10 +38,113 @@ impl GameState {
             keys_released: Default::default(),
             keys_held: Default::default(),
             delta_t: 0.0,
+            elapsed: 0.0,
+            tick: 0,
         }
     }
 }
 
+/// Who is playing on this machine.
+#[derive(Debug, Default)]
+pub struct LocalPlayer {
+    pub name: String,
+}
+
+/// Holds everyone in the lobby until the host starts the level, which it does once the room is
+/// full, or earlier if the host presses Return.
+#[derive(Debug, Default)]
+pub struct Lobby {
+    /// How many players the room was made for, including the host.
+    pub capacity: usize,
+    pub start_requested: bool,
+    /// Set on every peer once the host has started.
+    pub started: bool,
+}
+impl Lobby {
+    /// Whether the host should start, given the clients it has connected to.
+    pub fn ready(&self, peers: &[u32]) -> bool {
+        !peers.is_empty() && (self.start_requested || peers.len() + 1 >= self.capacity)
+    }
+}
+
+pub const MAX_CHAT_LENGTH: usize = 80;
+const CHAT_HISTORY: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct ChatEntry {
+    pub name: String,
+    pub text: String,
+    pub received_at: f64,
+}
+
+/// Chat typed by the local player, waiting to be sent, and the messages seen so far.
+#[derive(Debug, Default)]
+pub struct Chat {
+    /// What is being typed, if the chat box is open.
+    pub input: Option<String>,
+    pub outgoing: Vec<String>,
+    pub log: VecDeque<ChatEntry>,
+}
+impl Chat {
+    pub fn push(&mut self, name: String, text: String, received_at: f64) {
+        println!("{}: {}", name, text);
+
+        self.log.push_back(ChatEntry {
+            name,
+            text,
+            received_at,
+        });
+        while self.log.len() > CHAT_HISTORY {
+            self.log.pop_front();
+        }
+    }
+}
+
+pub const PING_DURATION: f64 = 4.0;
+
+#[derive(Debug, Clone)]
+pub struct Ping {
+    pub position: Vec2,
+    pub name: String,
+    pub placed_at: f64,
+}
+
+/// Pings and emotes asked for by the local player, waiting to be sent, and the pings currently
+/// on screen. Emotes are shown with the `Emoting` component on the player.
+#[derive(Debug, Default)]
+pub struct Gestures {
+    pub requested_pings: Vec<Vec2>,
+    pub requested_emotes: Vec<Emote>,
+    pub pings: Vec<Ping>,
+}
+
+/// The latest statistics for each peer, shown in the debug overlay and optionally written to a log
+/// file as they arrive.
+#[derive(Debug, Default)]
+pub struct NetworkStats {
+    pub visible: bool,
+    pub peers: Vec<PeerStats>,
+    pub log: Option<File>,
+}
+
+/// Where a replay has got to, and what the viewer has asked of it. Only active while a recording is
+/// being played back, when the keyboard drives the replay rather than the player.
+#[derive(Debug, Default)]
+pub struct Playback {
+    pub active: bool,
+    pub paused: bool,
+    /// Run a single tick while paused
+    pub step: bool,
+    pub seek_to: Option<u64>,
+    pub tick: u64,
+    pub length: u64,
+    pub diverged_at: Option<u64>,
+}
+impl Playback {
+    /// How far the arrow keys seek, five seconds at the usual tick rate.
+    pub const SEEK_TICKS: u64 = 300;
+}
+
 #[derive(Debug, Default)]
 pub struct GameCamera {
     size: (u32, u32),
@@ -62,6 +171,12 @@ impl GameCamera {
         let relative_pos = point - self.screen.top_left() - self.pos;
         ((relative_pos.x * self.scale.0) as i32, (relative_pos.y * self.scale.1) as i32)
     }
+    /// The inverse of `get_screen_point`, for working out what the mouse is pointing at.
+    pub fn get_world_point(&self, point: (i32, i32)) -> Vec2 {
+        Vec2::new(point.0 as f32 / self.scale.0, point.1 as f32 / self.scale.1)
+            + self.screen.top_left()
+            + self.pos
+    }
     pub fn try_get_screen_point(&self, point: Vec2) -> Option<(i32, i32)> {
         let relative_pos = point - self.screen.top_left() - self.pos;
         /*let outside = relative_pos.y > self.screen.top() || relative_pos.y < self.screen.bottom()
@@ -85,4 +200,4 @@ impl GameCamera {
             (rect.height() * self.scale.1.abs()) as u32,
         ))
     }
-}
\ No newline at end of file
+}
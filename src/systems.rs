@@ -1,32 +1,330 @@
 use std::collections::HashSet;
 
 use crate::components::{
-    Acceleration, Collider, FloorCollider, FloorCollision, Grounded, PlayerController, Position,
-    RenderDescriptor, Velocity,
+    Acceleration, Collider, Emote, Emoting, FloorCollider, FloorCollision, Grounded,
+    PlayerController, PlayerInput, PlayerName, Position, RenderDescriptor, Velocity,
+    EMOTE_DURATION,
+};
+use crate::networking::authority::Authority;
+use crate::networking::components::NetworkSend;
+use crate::networking::lockstep::Lockstep;
+use crate::networking::spawning::NetworkSpawner;
+use crate::networking::systems::ConnectionStatus;
+use crate::prefabs;
+use crate::resources::{
+    Chat, GameCamera, GameState, Gestures, Lobby, LocalPlayer, NetworkStats, Playback, SystemState,
+    MAX_CHAT_LENGTH, PING_DURATION,
 };
-use crate::resources::{GameCamera, GameState, SystemState};
 use crate::sat::intersection;
+use crate::text::{self, GLYPH_HEIGHT};
 use crate::util::Vec2;
+use crate::NetworkMode;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::keyboard::TextInputUtil;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::Color;
-use sdl2::render::WindowCanvas;
+use sdl2::rect::Rect as SDLRect;
+use sdl2::render::{BlendMode, WindowCanvas};
 use sdl2::EventPump;
-use specs::{Entities, Join, Read, ReadStorage, System, Write, WriteStorage};
+use specs::{Entities, Join, Read, ReadExpect, ReadStorage, System, Write, WriteStorage};
+
+pub const ACCELERATION_DUE_TO_GRAVITY: f32 = -130.0;
+pub const PLAYER_SPEED: f32 = 12.0;
+pub const JUMP_VELOCITY: f32 = 40.0;
 
 pub struct RenderSystem {
     canvas: WindowCanvas,
 }
 impl RenderSystem {
-    pub fn new(canvas: WindowCanvas) -> Self {
+    const CHAT_SCALE: i32 = 2;
+    const CHAT_LINES: usize = 8;
+    /// How long a chat message stays on screen, and how much of that it spends fading out.
+    const CHAT_VISIBLE: f64 = 8.0;
+    const CHAT_FADE: f64 = 2.0;
+
+    pub fn new(mut canvas: WindowCanvas) -> Self {
+        // Lets the chat log fade out
+        canvas.set_blend_mode(BlendMode::Blend);
+
         Self { canvas }
     }
+
+    /// Draws a row of dots, one of which is lit at a time, coloured by how far along connecting
+    /// to the room is, with the local player's name above and what is happening below.
+    fn draw_lobby(
+        &mut self,
+        camera: &GameCamera,
+        status: &ConnectionStatus,
+        lobby: &Lobby,
+        is_host: bool,
+        name: &str,
+        elapsed: f64,
+    ) {
+        const DOTS: i32 = 3;
+        const DOT_SIZE: i32 = 16;
+
+        let colour = match status {
+            ConnectionStatus::Connecting => Color::RGB(200, 200, 200),
+            ConnectionStatus::WaitingForPeer => Color::RGB(255, 200, 0),
+            ConnectionStatus::Connected(_) => Color::RGB(0, 255, 0),
+            ConnectionStatus::Lost(_) => Color::RGB(255, 0, 0),
+        };
+        let lit = (elapsed * 2.0) as i32 % DOTS;
+
+        let (width, height) = camera.get_size();
+        let left = width as i32 / 2 - DOTS * DOT_SIZE;
+        let top = height as i32 / 2 - DOT_SIZE / 2;
+
+        for i in 0..DOTS {
+            let brightness = if i == lit { 1.0 } else { 0.3 };
+            self.canvas.set_draw_color(Color::RGB(
+                (colour.r as f64 * brightness) as u8,
+                (colour.g as f64 * brightness) as u8,
+                (colour.b as f64 * brightness) as u8,
+            ));
+
+            let rect = SDLRect::new(
+                left + i * DOT_SIZE * 2,
+                top,
+                DOT_SIZE as u32,
+                DOT_SIZE as u32,
+            );
+            if let Err(e) = self.canvas.fill_rect(rect) {
+                eprintln!("{}", e);
+            }
+        }
+
+        let description = match status {
+            ConnectionStatus::Connecting => "Connecting".to_string(),
+            ConnectionStatus::WaitingForPeer => "Waiting for players".to_string(),
+            ConnectionStatus::Connected(_) if lobby.started => "Starting".to_string(),
+            ConnectionStatus::Connected(peers) if is_host => format!(
+                "{} of {} players, press Return to start",
+                peers.len() + 1,
+                lobby.capacity
+            ),
+            ConnectionStatus::Connected(_) => "Waiting for the host to start".to_string(),
+            ConnectionStatus::Lost(reason) => reason.clone(),
+        };
+
+        self.draw_centred_text(name, width as i32 / 2, top - 4 * DOT_SIZE, 4, Color::WHITE);
+        self.draw_centred_text(
+            &description,
+            width as i32 / 2,
+            top + 3 * DOT_SIZE,
+            2,
+            colour,
+        );
+    }
+
+    /// Draws the most recent chat messages in the bottom left corner, above the chat box if it is
+    /// open. Messages fade away after a while, unless the chat box is open.
+    fn draw_chat(&mut self, camera: &GameCamera, chat: &Chat, elapsed: f64) {
+        const MARGIN: i32 = 8;
+        let line_height = (GLYPH_HEIGHT + 2) * Self::CHAT_SCALE;
+
+        let (_, height) = camera.get_size();
+        let mut y = height as i32 - MARGIN - line_height;
+
+        if let Some(input) = &chat.input {
+            let cursor = if (elapsed * 2.0) as i64 % 2 == 0 {
+                "_"
+            } else {
+                ""
+            };
+            let line = format!("> {}{}", input, cursor);
+            if let Err(e) = text::draw_text(
+                &mut self.canvas,
+                &line,
+                MARGIN,
+                y,
+                Self::CHAT_SCALE,
+                Color::WHITE,
+            ) {
+                eprintln!("{}", e);
+            }
+        }
+        y -= line_height;
+
+        for entry in chat.log.iter().rev().take(Self::CHAT_LINES) {
+            let age = elapsed - entry.received_at;
+            let opacity = if chat.input.is_some() {
+                1.0
+            } else {
+                ((Self::CHAT_VISIBLE - age) / Self::CHAT_FADE).clamp(0.0, 1.0)
+            };
+            if opacity <= 0.0 {
+                break;
+            }
+            let alpha = (opacity * 255.0) as u8;
+
+            let name = format!("{}: ", entry.name);
+            let result = text::draw_text(
+                &mut self.canvas,
+                &name,
+                MARGIN,
+                y,
+                Self::CHAT_SCALE,
+                Color::RGBA(255, 200, 0, alpha),
+            )
+            .and_then(|_| {
+                text::draw_text(
+                    &mut self.canvas,
+                    &entry.text,
+                    MARGIN + text::text_width(&name, Self::CHAT_SCALE),
+                    y,
+                    Self::CHAT_SCALE,
+                    Color::RGBA(255, 255, 255, alpha),
+                )
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+            }
+
+            y -= line_height;
+        }
+    }
+
+    /// Draws a pulsing square on each ping, labelled with who placed it, fading out towards the
+    /// end.
+    fn draw_pings(&mut self, camera: &GameCamera, gestures: &Gestures, elapsed: f64) {
+        const SIZE: i32 = 12;
+
+        for ping in &gestures.pings {
+            let age = elapsed - ping.placed_at;
+            let alpha = ((PING_DURATION - age).clamp(0.0, 1.0) * 255.0) as u8;
+            let colour = Color::RGBA(0, 255, 255, alpha);
+
+            let (x, y) = camera.get_screen_point(ping.position);
+            let size = SIZE + ((age * 6.0).sin() * 3.0) as i32;
+
+            self.canvas.set_draw_color(colour);
+            let rect = SDLRect::new(x - size / 2, y - size / 2, size as u32, size as u32);
+            if let Err(e) = self.canvas.draw_rect(rect) {
+                eprintln!("{}", e);
+            }
+
+            self.draw_centred_text(&ping.name, x, y - size - GLYPH_HEIGHT * 2, 2, colour);
+        }
+    }
+
+    /// Draws a line of statistics for each peer in the top left corner, over a dark background so
+    /// it stays readable in front of the level.
+    fn draw_network_stats(&mut self, stats: &NetworkStats) {
+        const MARGIN: i32 = 8;
+        const SCALE: i32 = 2;
+        let line_height = (GLYPH_HEIGHT + 2) * SCALE;
+
+        let mut lines = vec!["NETWORK (F3)".to_string()];
+        if stats.peers.is_empty() {
+            lines.push("No peers".to_string());
+        }
+        for peer in &stats.peers {
+            let rtt = peer
+                .rtt
+                .map_or("-".to_string(), |rtt| format!("{:.0}ms", rtt * 1000.0));
+            let depth = peer
+                .snapshot_depth
+                .map_or("-".to_string(), |depth| depth.to_string());
+
+            lines.push(format!(
+                "{} ({}){}",
+                peer.name,
+                peer.client_id,
+                if peer.relayed { " relayed" } else { "" }
+            ));
+            lines.push(format!(
+                "  rtt {} up {:.1}kb/s down {:.1}kb/s loss {:.1}% buffer {}",
+                rtt,
+                peer.sent / 1000.0,
+                peer.received / 1000.0,
+                peer.loss * 100.0,
+                depth
+            ));
+        }
+
+        let width = lines
+            .iter()
+            .map(|line| text::text_width(line, SCALE))
+            .max()
+            .unwrap_or_default();
+        self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 180));
+        let background = SDLRect::new(
+            MARGIN / 2,
+            MARGIN / 2,
+            (width + MARGIN) as u32,
+            (lines.len() as i32 * line_height + MARGIN) as u32,
+        );
+        if let Err(e) = self.canvas.fill_rect(background) {
+            eprintln!("{}", e);
+        }
+
+        for (i, line) in lines.iter().enumerate() {
+            let y = MARGIN + i as i32 * line_height;
+            if let Err(e) = text::draw_text(&mut self.canvas, line, MARGIN, y, SCALE, Color::GREEN)
+            {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    fn draw_playback(&mut self, playback: &Playback) {
+        let mut status = format!(
+            "REPLAY {}/{}",
+            playback.tick.min(playback.length),
+            playback.length
+        );
+        if playback.paused {
+            status.push_str(" PAUSED");
+        }
+        let colour = match playback.diverged_at {
+            Some(tick) => {
+                status.push_str(&format!(" DIVERGED AT {}", tick));
+                Color::RED
+            }
+            None => Color::WHITE,
+        };
+
+        self.draw_status(&status, colour);
+    }
+
+    /// A line of text in the top right corner, out of the way of the network overlay.
+    fn draw_status(&mut self, status: &str, colour: Color) {
+        const MARGIN: i32 = 8;
+        const SCALE: i32 = 2;
+
+        let x = self.canvas.viewport().width() as i32 - MARGIN - text::text_width(status, SCALE);
+        if let Err(e) = text::draw_text(&mut self.canvas, status, x, MARGIN, SCALE, colour) {
+            eprintln!("{}", e);
+        }
+    }
+
+    fn draw_centred_text(&mut self, text: &str, x: i32, y: i32, scale: i32, colour: Color) {
+        let left = x - text::text_width(text, scale) / 2;
+
+        if let Err(e) = text::draw_text(&mut self.canvas, text, left, y, scale, colour) {
+            eprintln!("{}", e);
+        }
+    }
 }
 impl<'a> System<'a> for RenderSystem {
     type SystemData = (
         ReadStorage<'a, Position>,
         ReadStorage<'a, RenderDescriptor>,
+        ReadStorage<'a, PlayerName>,
         Read<'a, GameCamera>,
+        Read<'a, GameState>,
+        Read<'a, ConnectionStatus>,
+        Read<'a, LocalPlayer>,
+        Read<'a, Chat>,
+        ReadStorage<'a, Emoting>,
+        Read<'a, Gestures>,
+        Read<'a, NetworkStats>,
+        Read<'a, Playback>,
+        Read<'a, Lockstep>,
+        Read<'a, Lobby>,
+        ReadExpect<'a, NetworkMode>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
@@ -35,7 +333,36 @@ impl<'a> System<'a> for RenderSystem {
         self.canvas.set_draw_color(Color::RGB(0, 0, 0));
         self.canvas.clear();
 
-        let (position, descriptor, camera) = data;
+        let (
+            position,
+            descriptor,
+            player_name,
+            camera,
+            game_state,
+            connection_status,
+            local_player,
+            chat,
+            emoting,
+            gestures,
+            network_stats,
+            playback,
+            lockstep,
+            lobby,
+            network_mode,
+        ) = data;
+
+        if let SystemState::Lobby = game_state.system_state {
+            self.draw_lobby(
+                &camera,
+                &connection_status,
+                &lobby,
+                matches!(*network_mode, NetworkMode::Host),
+                &local_player.name,
+                game_state.elapsed,
+            );
+            self.canvas.present();
+            return;
+        }
 
         for (pos, desc) in (&position, &descriptor).join() {
             self.canvas.set_draw_color(desc.colour());
@@ -46,41 +373,228 @@ impl<'a> System<'a> for RenderSystem {
                 }
             }
         }
+
+        for (pos, desc, name) in (&position, &descriptor, &player_name).join() {
+            if let Some(rect) = camera.try_process_rect(pos.0, desc.rectangle()) {
+                let x = rect.x() + rect.width() as i32 / 2;
+                let y = rect.y() - (GLYPH_HEIGHT + 2) * 2;
+                self.draw_centred_text(&name.0, x, y, 2, Color::WHITE);
+            }
+        }
+
+        for (pos, desc, emoting) in (&position, &descriptor, &emoting).join() {
+            let age = game_state.elapsed - emoting.started_at;
+            let alpha = ((EMOTE_DURATION - age).clamp(0.0, 1.0) * 255.0) as u8;
+
+            if let Some(rect) = camera.try_process_rect(pos.0, desc.rectangle()) {
+                // Drawn above the name, which every player has
+                let x = rect.x() + rect.width() as i32 / 2;
+                let y = rect.y() - (GLYPH_HEIGHT + 2) * 2 - (GLYPH_HEIGHT + 2) * 3;
+                self.draw_centred_text(
+                    emoting.emote.label(),
+                    x,
+                    y,
+                    3,
+                    Color::RGBA(255, 200, 0, alpha),
+                );
+            }
+        }
+
+        self.draw_pings(&camera, &gestures, game_state.elapsed);
+
+        self.draw_chat(&camera, &chat, game_state.elapsed);
+
+        if network_stats.visible {
+            self.draw_network_stats(&network_stats);
+        }
+        if playback.active {
+            self.draw_playback(&playback);
+        }
+        if let Some(reason) = &lockstep.ended {
+            self.draw_status(reason, Color::RED);
+        } else if let Some(desync) = lockstep.desync {
+            let status = format!(
+                "DESYNC ON TICK {} (CLIENT {})",
+                desync.tick, desync.client_id
+            );
+            self.draw_status(&status, Color::RED);
+        }
         self.canvas.present();
     }
 }
 
 pub struct EventSystem {
     event_pump: EventPump,
+    text_input: TextInputUtil,
 }
 impl EventSystem {
-    pub fn new(event_pump: EventPump) -> Self {
-        Self { event_pump }
+    pub fn new(event_pump: EventPump, text_input: TextInputUtil) -> Self {
+        // Text input is only wanted while the chat box is open
+        text_input.stop();
+
+        Self {
+            event_pump,
+            text_input,
+        }
+    }
+}
+impl EventSystem {
+    fn control_playback(&mut self, game_state: &mut GameState, playback: &mut Playback) {
+        for event in self.event_pump.poll_iter() {
+            let keycode = match event {
+                Event::Quit { .. } => {
+                    game_state.system_state = SystemState::Quit;
+                    continue;
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => keycode,
+                _ => continue,
+            };
+
+            match keycode {
+                Keycode::Escape => game_state.system_state = SystemState::Quit,
+                Keycode::Space => playback.paused = !playback.paused,
+                Keycode::Period => {
+                    playback.paused = true;
+                    playback.step = true;
+                }
+                Keycode::Left => {
+                    playback.seek_to = Some(playback.tick.saturating_sub(Playback::SEEK_TICKS))
+                }
+                Keycode::Right => playback.seek_to = Some(playback.tick + Playback::SEEK_TICKS),
+                Keycode::Home => playback.seek_to = Some(0),
+                _ => {}
+            }
+        }
     }
 }
 impl<'a> System<'a> for EventSystem {
-    type SystemData = Write<'a, GameState>;
+    type SystemData = (
+        Write<'a, GameState>,
+        Write<'a, Chat>,
+        Write<'a, Gestures>,
+        Read<'a, GameCamera>,
+        Write<'a, NetworkStats>,
+        Write<'a, Playback>,
+        Write<'a, Lobby>,
+    );
 
     fn run(&mut self, data: Self::SystemData) {
-        let mut game_state = data;
+        let (
+            mut game_state,
+            mut chat,
+            mut gestures,
+            camera,
+            mut network_stats,
+            mut playback,
+            mut lobby,
+        ) = data;
+
+        // A replay supplies its own keys, so the keyboard only drives playback
+        if playback.active {
+            self.control_playback(&mut game_state, &mut playback);
+            return;
+        }
+
+        let playing =
+            matches!(game_state.system_state, SystemState::Running) && chat.input.is_none();
 
         for event in self.event_pump.poll_iter() {
             match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
+                Event::Quit { .. } => game_state.system_state = SystemState::Quit,
+                Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => game_state.system_state = SystemState::Quit,
+                } => {
+                    if chat.input.take().is_some() {
+                        self.text_input.stop();
+                    } else {
+                        game_state.system_state = SystemState::Quit;
+                    }
+                }
+                // Only the host's start counts, and it is sent on to everyone else
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    ..
+                } if matches!(game_state.system_state, SystemState::Lobby) => {
+                    lobby.start_requested = true
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    ..
+                } if matches!(game_state.system_state, SystemState::Running) => {
+                    match chat.input.take() {
+                        Some(input) => {
+                            self.text_input.stop();
+
+                            let input = input.trim();
+                            if !input.is_empty() {
+                                chat.outgoing.push(input.to_string());
+                            }
+                        }
+                        None => {
+                            self.text_input.start();
+                            chat.input = Some(String::new());
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => network_stats.visible = !network_stats.visible,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => {
+                    if let Some(input) = chat.input.as_mut() {
+                        input.pop();
+                    }
+                }
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } if playing => gestures
+                    .requested_pings
+                    .push(camera.get_world_point((x, y))),
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat: false,
+                    ..
+                } if playing => {
+                    let emote = match keycode {
+                        Keycode::Num1 => Emote::Wave,
+                        Keycode::Num2 => Emote::Yes,
+                        Keycode::Num3 => Emote::No,
+                        Keycode::Num4 => Emote::Help,
+                        _ => continue,
+                    };
+                    gestures.requested_emotes.push(emote);
+                }
+                Event::TextInput { text, .. } => {
+                    if let Some(input) = chat.input.as_mut() {
+                        let space = MAX_CHAT_LENGTH.saturating_sub(input.chars().count());
+                        input.extend(text.chars().take(space));
+                    }
+                }
                 _ => {}
             }
         }
 
-        let keys = self
-            .event_pump
-            .keyboard_state()
-            .pressed_scancodes()
-            .filter_map(Keycode::from_scancode)
-            .collect::<HashSet<Keycode>>();
+        // Typing in the chat box should not move the player
+        let keys = if chat.input.is_some() {
+            HashSet::new()
+        } else {
+            self.event_pump
+                .keyboard_state()
+                .pressed_scancodes()
+                .filter_map(Keycode::from_scancode)
+                .collect::<HashSet<Keycode>>()
+        };
 
         game_state.keys_pressed = &keys - &game_state.keys_held;
         game_state.keys_released = &game_state.keys_held - &keys;
@@ -106,7 +620,7 @@ impl<'a> System<'a> for EntityMovementSystem {
         let dt = game_state.delta_t;
 
         for accel in (&mut accel).join() {
-            accel.0.y = -130.0;
+            accel.0.y = ACCELERATION_DUE_TO_GRAVITY;
         }
 
         for ground in (&mut grounded).join() {
@@ -123,47 +637,184 @@ impl<'a> System<'a> for EntityMovementSystem {
     }
 }
 
+pub struct PlayerInputSystem;
+impl<'a> System<'a> for PlayerInputSystem {
+    type SystemData = (
+        WriteStorage<'a, PlayerInput>,
+        ReadStorage<'a, PlayerController>,
+        Read<'a, GameState>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        use specs::Join;
+
+        let (mut player_input, player_controlled, game_state) = data;
+
+        for (input, _) in (&mut player_input, &player_controlled).join() {
+            let keys = &game_state.keys_held;
+
+            input.left = keys.contains(&Keycode::A);
+            input.right = keys.contains(&Keycode::D);
+            input.jump = keys.contains(&Keycode::W) || keys.contains(&Keycode::Space);
+        }
+    }
+}
+
 pub struct PlayerMovementSystem;
 impl<'a> System<'a> for PlayerMovementSystem {
     type SystemData = (
-        WriteStorage<'a, Position>,
         WriteStorage<'a, Velocity>,
         WriteStorage<'a, Grounded>,
-        ReadStorage<'a, PlayerController>,
-        Read<'a, GameState>,
+        ReadStorage<'a, PlayerInput>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
         use specs::Join;
 
-        let (mut position, mut velocity, mut grounded, player_controlled, game_state) = data;
+        let (mut velocity, mut grounded, player_input) = data;
 
-        for (_pos, vel, ground, _) in (
-            &mut position,
-            &mut velocity,
-            &mut grounded,
-            &player_controlled,
-        )
-            .join()
-        {
+        for (vel, ground, input) in (&mut velocity, &mut grounded, &player_input).join() {
             let mut vx = 0.0f32;
-            for key in &game_state.keys_held {
-                use Keycode::*;
-                match key {
-                    A => vx += -12.0,
-                    D => vx += 12.0,
-                    W | Space if ground.0 => {
-                        vel.0.y = 40.0;
-                        ground.0 = false;
-                    }
-                    _ => {}
-                }
+            if input.left {
+                vx -= PLAYER_SPEED;
+            }
+            if input.right {
+                vx += PLAYER_SPEED;
+            }
+            if input.jump && ground.0 {
+                vel.0.y = JUMP_VELOCITY;
+                ground.0 = false;
             }
             vel.0.x = vx;
         }
     }
 }
 
+/// Lets players push networked entities sideways. Entities are only pushed by the peer with
+/// authority over them; everyone else records that the local player is touching them, so the
+/// `NetworkHandler` can ask for authority.
+pub struct PushSystem;
+impl<'a> System<'a> for PushSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        ReadStorage<'a, Collider<'static>>,
+        ReadStorage<'a, PlayerInput>,
+        ReadStorage<'a, PlayerController>,
+        WriteStorage<'a, Authority>,
+        Read<'a, GameState>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            mut position,
+            mut velocity,
+            collider,
+            player_input,
+            player_controller,
+            mut authority,
+            game_state,
+        ) = data;
+
+        let players = (&entities, &position, &velocity, &player_input)
+            .join()
+            .map(|(entity, pos, vel, _)| (entity, pos.0, vel.0))
+            .collect::<Vec<_>>();
+
+        for (player, player_pos, player_vel) in players {
+            let player_collider = match collider.get(player) {
+                Some(collider) => collider,
+                None => continue,
+            };
+
+            for (pushed, pos, vel, pushed_collider, authority) in (
+                &entities,
+                &mut position,
+                &mut velocity,
+                &collider,
+                &mut authority,
+            )
+                .join()
+            {
+                if pushed == player {
+                    continue;
+                }
+
+                let n = match intersection(
+                    pushed_collider.shape(),
+                    pos.0,
+                    player_collider.shape(),
+                    player_pos,
+                ) {
+                    Some(n) if n.magnitude() != 0.0 && n.x.abs() >= n.y.abs() => n,
+                    _ => continue,
+                };
+
+                if player_controller.contains(player) {
+                    authority.touch(game_state.elapsed);
+                }
+                if !authority.is_local() {
+                    continue;
+                }
+
+                let direction = (pos.0.x - player_pos.x).signum();
+                pos.0.x += direction * n.magnitude();
+                vel.0.x = player_vel.x;
+            }
+        }
+    }
+}
+
+pub struct BoxSpawnSystem;
+impl<'a> System<'a> for BoxSpawnSystem {
+    type SystemData = (
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, PlayerController>,
+        Read<'a, GameState>,
+        Write<'a, NetworkSpawner>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        use specs::Join;
+
+        let (position, player_controlled, game_state, mut spawner) = data;
+
+        if !game_state.keys_pressed.contains(&Keycode::B) {
+            return;
+        }
+
+        for (pos, _) in (&position, &player_controlled).join() {
+            spawner.spawn(prefabs::crate_box(pos.0 + Vec2::new(0.0, 2.0), 1.0));
+        }
+    }
+}
+
+pub struct KillPlaneSystem;
+impl KillPlaneSystem {
+    const KILL_PLANE: f32 = -50.0;
+}
+impl<'a> System<'a> for KillPlaneSystem {
+    type SystemData = (
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, NetworkSend>,
+        Write<'a, NetworkSpawner>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        use specs::Join;
+
+        let (position, network_send, mut spawner) = data;
+
+        for (pos, network_send) in (&position, &network_send).join() {
+            if pos.0.y < Self::KILL_PLANE {
+                spawner.despawn(network_send.network_id());
+            }
+        }
+    }
+}
+
 pub struct FloorColliderSystem;
 impl<'a> System<'a> for FloorColliderSystem {
     type SystemData = (
@@ -193,6 +844,23 @@ impl<'a> System<'a> for FloorColliderSystem {
 
         let _dt = game_state.delta_t;
 
+        // Objects are pushed out of each floor in turn, so the order the floors are visited in
+        // decides where an object ends up. Joins go by entity id, which depends on the order
+        // things were created in, so the floors are sorted by where they are instead: top to
+        // bottom, then left to right.
+        let mut floors = (&entities, &collider, &floor_collider, &position)
+            .join()
+            .map(|(floor, floor_collider, _, pos)| (floor, floor_collider, *pos))
+            .collect::<Vec<_>>();
+        floors.sort_by(|(a, _, a_pos), (b, _, b_pos)| {
+            b_pos
+                .0
+                .y
+                .total_cmp(&a_pos.0.y)
+                .then(a_pos.0.x.total_cmp(&b_pos.0.x))
+                .then(a.id().cmp(&b.id()))
+        });
+
         'objects: for (colliding, vel, accel, player_collider, mut ground, _) in (
             &entities,
             &mut velocity,
@@ -203,14 +871,7 @@ impl<'a> System<'a> for FloorColliderSystem {
         )
             .join()
         {
-            'floors: for (floor, floor_collider, _) in
-                (&entities, &collider, &floor_collider).join()
-            {
-                let floor_pos = if let Some(pos) = position.get(floor) {
-                    *pos
-                } else {
-                    continue 'floors;
-                };
+            for &(_, floor_collider, floor_pos) in &floors {
                 let obj_pos = if let Some(pos) = position.get_mut(colliding) {
                     pos
                 } else {